@@ -1,110 +1,507 @@
-use std::collections::VecDeque;
+use crate::model::Canvas;
+use crate::selection::Selection;
 use egui::*;
+use std::collections::BTreeMap;
+use std::mem::size_of;
+use std::ops::Range;
 
-#[derive(Clone)]
+/// A full copy of the canvas, used to save and load projects.
+#[derive(Clone, PartialEq, Debug)]
 pub struct CanvasState {
     pub layers: usize,
     pub active_layer: usize,
     pub layer_names: Vec<String>,
     pub layer_name_cnt: usize,
     pub layers_to_show: Vec<bool>,
+    pub frames: usize,
+    pub active_frame: usize,
+    pub frame_durations: Vec<u32>,
     pub pixels: Vec<Color32>,
-    pub squares: Vec<Shape>,
-}
-
-impl CanvasState {
-    pub fn new(layers: usize,
-        active_layer: usize,
-        layer_names: Vec<String>,
-        layer_name_cnt: usize,
-        layers_to_show: Vec<bool>,
-        pixels: Vec<Color32>,
-        squares: Vec<Shape>
-    ) -> Self {
+}
+
+/// Everything about the canvas besides its pixels. It is small, so changes keep a
+/// copy from before and after them instead of a diff.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct CanvasMeta {
+    pub layers: usize,
+    pub active_layer: usize,
+    pub layer_names: Vec<String>,
+    pub layer_name_cnt: usize,
+    pub layers_to_show: Vec<bool>,
+    pub frames: usize,
+    pub active_frame: usize,
+    pub frame_durations: Vec<u32>,
+}
+
+impl CanvasMeta {
+    fn memory(&self) -> usize {
+        size_of::<Self>()
+            + self.layer_names.iter().map(|n| n.len()).sum::<usize>()
+            + self.layer_names.len() * size_of::<String>()
+            + self.layers_to_show.len()
+            + self.frame_durations.len() * size_of::<u32>()
+    }
+}
+
+/// Run-length encoded pixels. Pixel art is mostly long runs of one color, so this
+/// keeps fills, new layers and empty frames in the history small.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct CompressedPixels(Vec<(u32, Color32)>);
+
+impl CompressedPixels {
+    pub fn encode(pixels: impl IntoIterator<Item = Color32>) -> Self {
+        let mut runs: Vec<(u32, Color32)> = Vec::new();
+        for color in pixels {
+            match runs.last_mut() {
+                Some((count, last)) if *last == color && *count < u32::MAX => *count += 1,
+                _ => runs.push((1, color)),
+            }
+        }
+        Self(runs)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Color32> + '_ {
+        self.0
+            .iter()
+            .flat_map(|&(count, color)| std::iter::repeat_n(color, count as usize))
+    }
+
+    pub fn decode(&self) -> Vec<Color32> {
+        let mut pixels = Vec::with_capacity(self.len());
+        pixels.extend(self.iter());
+        pixels
+    }
+
+    /// Number of pixels once decoded.
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|&(count, _)| count as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn memory(&self) -> usize {
+        self.0.len() * size_of::<(u32, Color32)>()
+    }
+}
+
+/// A run-length encoded selection mask, as runs that alternate between unselected and
+/// selected pixels, starting with unselected ones.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CompressedSelection {
+    width: usize,
+    height: usize,
+    runs: Vec<u32>,
+}
+
+impl CompressedSelection {
+    pub fn encode(selection: &Selection) -> Self {
+        let (width, height) = selection.get_size();
+        let mut runs = vec![0];
+        let mut selected = false;
+        for &m in selection.get_mask() {
+            if m != selected {
+                runs.push(0);
+                selected = m;
+            }
+            *runs.last_mut().unwrap() += 1;
+        }
         Self {
-            layers,
-            active_layer,
-            layer_names,
-            layer_name_cnt,
-            layers_to_show,
-            pixels,
-            squares,
+            width,
+            height,
+            runs,
         }
     }
+
+    pub fn decode(&self) -> Selection {
+        let mut mask = Vec::with_capacity(self.width * self.height);
+        for (i, &run) in self.runs.iter().enumerate() {
+            mask.extend(std::iter::repeat_n(i % 2 == 1, run as usize));
+        }
+        Selection::from_mask(self.width, self.height, mask)
+    }
+
+    fn memory(&self) -> usize {
+        size_of::<Self>() + self.runs.len() * size_of::<u32>()
+    }
+}
+
+/// Pixels overwritten in place, starting at `start` in the canvas pixel buffer.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PixelSpan {
+    pub start: usize,
+    pub before: CompressedPixels,
+    pub after: CompressedPixels,
 }
 
-const STACK_SIZE: usize = 15;
+/// One step of a change. Every edit can be reverted exactly, so a change is undone by
+/// reverting its edits in reverse order.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Edit {
+    Pixels(Vec<PixelSpan>),
+    /// A layer inserted at `layer_idx`, holding its cel for every frame in order.
+    InsertLayer {
+        layer_idx: usize,
+        cels: CompressedPixels,
+    },
+    RemoveLayer {
+        layer_idx: usize,
+        cels: CompressedPixels,
+    },
+    /// A frame inserted at `frame_idx`, holding the cel of every layer in order.
+    InsertFrame {
+        frame_idx: usize,
+        cels: CompressedPixels,
+    },
+    RemoveFrame {
+        frame_idx: usize,
+        cels: CompressedPixels,
+    },
+    MoveFrame {
+        from_idx: usize,
+        to_idx: usize,
+    },
+    /// The selection replaced, `None` being no selection.
+    Selection {
+        before: Option<CompressedSelection>,
+        after: Option<CompressedSelection>,
+    },
+}
+
+impl Edit {
+    fn memory(&self) -> usize {
+        size_of::<Self>()
+            + match self {
+                Edit::Pixels(spans) => spans
+                    .iter()
+                    .map(|s| size_of::<PixelSpan>() + s.before.memory() + s.after.memory())
+                    .sum(),
+                Edit::InsertLayer { cels, .. }
+                | Edit::RemoveLayer { cels, .. }
+                | Edit::InsertFrame { cels, .. }
+                | Edit::RemoveFrame { cels, .. } => cels.memory(),
+                Edit::MoveFrame { .. } => 0,
+                Edit::Selection { before, after } => [before, after]
+                    .iter()
+                    .flat_map(|s| s.as_ref())
+                    .map(CompressedSelection::memory)
+                    .sum(),
+            }
+    }
+}
+
+/// Everything one action did to the canvas.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Change {
+    /// What the action was, shown in the history.
+    pub name: String,
+    pub before: CanvasMeta,
+    pub after: CanvasMeta,
+    pub edits: Vec<Edit>,
+}
+
+impl Change {
+    /// Rough number of bytes the change keeps alive.
+    pub fn memory(&self) -> usize {
+        self.name.len()
+            + self.before.memory()
+            + self.after.memory()
+            + self.edits.iter().map(Edit::memory).sum::<usize>()
+    }
+}
+
+/// The oldest changes are dropped once either limit is reached.
+const HISTORY_LIMIT: usize = 10_000;
+const HISTORY_MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+/// Name for edits that were still in progress when the history was moved.
+const UNRECORDED_NAME: &str = "Unfinished edit";
+/// Name of the state the history starts from.
+const ROOT_NAME: &str = "Start";
+
+struct HistoryNode {
+    /// The change that leads here from the parent, `None` only for the root.
+    change: Option<Change>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// The child redo goes to, which is the one visited last.
+    redo_child: Option<usize>,
+    /// How many times the path from the root branches off to a later child, used to
+    /// indent branches in the history.
+    branch_depth: usize,
+}
+
+/// A row of the history, as shown in the history panel.
+pub struct HistoryEntry<'a> {
+    pub id: usize,
+    pub name: &'a str,
+    pub branch_depth: usize,
+    /// Whether the change is part of the canvas as it is now.
+    pub applied: bool,
+    pub current: bool,
+}
+
+/// The history as a tree. Undoing and then making a change starts a new branch
+/// instead of throwing away what was undone, so every state stays reachable.
 pub struct ChangeManager {
-    current_state: CanvasState,
-    undo_stk: VecDeque<CanvasState>,
-    redo_stk: VecDeque<CanvasState>,
+    /// Ids only ever grow, so the nodes are in the order they were made.
+    nodes: BTreeMap<usize, HistoryNode>,
+    root: usize,
+    current: usize,
+    next_id: usize,
+    /// Bytes used by the changes in the tree.
+    memory: usize,
+}
+
+impl Default for ChangeManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ChangeManager {
-    pub fn new(initial_state: CanvasState) -> Self {
+    pub fn new() -> Self {
+        let root = HistoryNode {
+            change: None,
+            parent: None,
+            children: Vec::new(),
+            redo_child: None,
+            branch_depth: 0,
+        };
         Self {
-            current_state: initial_state,
-            undo_stk: VecDeque::with_capacity(STACK_SIZE),
-            redo_stk: VecDeque::with_capacity(STACK_SIZE),
+            nodes: BTreeMap::from([(0, root)]),
+            root: 0,
+            current: 0,
+            next_id: 1,
+            memory: 0,
         }
     }
 
+    fn node(&self, id: usize) -> &HistoryNode {
+        &self.nodes[&id]
+    }
+
+    fn node_mut(&mut self, id: usize) -> &mut HistoryNode {
+        self.nodes.get_mut(&id).unwrap()
+    }
+
     pub fn is_undo_empty(&self) -> bool {
-        self.undo_stk.is_empty()
+        self.current == self.root
     }
 
     pub fn is_redo_empty(&self) -> bool {
-        self.redo_stk.is_empty()
+        self.node(self.current).redo_child.is_none()
     }
 
-    pub fn push_undo(&mut self, s: CanvasState) {
-        // if overrun
-        if self.undo_stk.len() >= STACK_SIZE {
-            self.undo_stk.pop_front();
-        }
+    pub fn undo_name(&self) -> Option<&str> {
+        self.node(self.current)
+            .change
+            .as_ref()
+            .map(|c| c.name.as_str())
+    }
 
-        self.undo_stk.push_back(s);
+    pub fn redo_name(&self) -> Option<&str> {
+        let child = self.node(self.current).redo_child?;
+        self.node(child).change.as_ref().map(|c| c.name.as_str())
     }
 
-    pub fn push_redo(&mut self, s: CanvasState) {
-        // if overrun
-        if self.redo_stk.len() >= STACK_SIZE {
-            self.redo_stk.pop_front();
+    /// Number of states kept, including the one the history starts from.
+    pub fn history_len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The states in `rows`, oldest first.
+    pub fn history_entries(&self, rows: Range<usize>) -> Vec<HistoryEntry<'_>> {
+        let applied = self.path_from_root(self.current);
+        self.nodes
+            .iter()
+            .skip(rows.start)
+            .take(rows.len())
+            .map(|(&id, node)| HistoryEntry {
+                id,
+                name: node.change.as_ref().map_or(ROOT_NAME, |c| c.name.as_str()),
+                branch_depth: node.branch_depth - self.node(self.root).branch_depth,
+                applied: applied.contains(&id),
+                current: id == self.current,
+            })
+            .collect()
+    }
+
+    /// Ids from the root down to `id`.
+    fn path_from_root(&self, id: usize) -> Vec<usize> {
+        let mut path = vec![id];
+        while let Some(parent) = self.node(*path.last().unwrap()).parent {
+            path.push(parent);
         }
+        path.reverse();
+        path
+    }
+
+    /// Records everything done to the canvas since the last recorded change as a
+    /// single undo step. Does nothing if the canvas did not change.
+    pub fn push_change(&mut self, canvas: &mut Canvas, name: impl Into<String>) {
+        let Some(change) = canvas.take_change(name.into()) else {
+            return;
+        };
 
-        self.redo_stk.push_back(s);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.memory += change.memory();
+
+        let parent = self.node_mut(self.current);
+        let branch_depth = parent.branch_depth + usize::from(!parent.children.is_empty());
+        parent.children.push(id);
+        parent.redo_child = Some(id);
+        self.nodes.insert(
+            id,
+            HistoryNode {
+                change: Some(change),
+                parent: Some(self.current),
+                children: Vec::new(),
+                redo_child: None,
+                branch_depth,
+            },
+        );
+        self.current = id;
+
+        self.trim();
     }
 
-    pub fn push_new_state(&mut self, c: CanvasState) {
-        if !self.is_redo_empty() {
-            self.redo_stk.clear();
+    /// Drops the oldest states until the history fits in its limits again. The root
+    /// moves one step towards the current state at a time, taking every branch that
+    /// does not lead there with it.
+    fn trim(&mut self) {
+        while (self.nodes.len() > HISTORY_LIMIT || self.memory > HISTORY_MEMORY_BUDGET)
+            && self.root != self.current
+        {
+            let path = self.path_from_root(self.current);
+            let (old_root, new_root) = (path[0], path[1]);
+
+            let mut to_remove: Vec<usize> = self
+                .node(old_root)
+                .children
+                .iter()
+                .copied()
+                .filter(|&child| child != new_root)
+                .collect();
+            while let Some(id) = to_remove.pop() {
+                let node = self.nodes.remove(&id).unwrap();
+                self.memory -= node.change.map_or(0, |c| c.memory());
+                to_remove.extend(node.children);
+            }
+            self.nodes.remove(&old_root);
+
+            let root = self.node_mut(new_root);
+            root.parent = None;
+            let dropped = root.change.take();
+            self.memory -= dropped.map_or(0, |c| c.memory());
+            self.root = new_root;
         }
+    }
 
-        let mut temp_state = c;
-        std::mem::swap(&mut temp_state, &mut self.current_state);
-        // theoretically, prev_state now holds the old current state, and current_state holds the new state
+    pub fn undo(&mut self, canvas: &mut Canvas) {
+        // Pixels drawn since the last change have to be recorded first, the changes
+        // only make sense on top of the canvas they were taken from
+        if canvas.has_pending_edits() {
+            self.push_change(canvas, UNRECORDED_NAME);
+        }
 
-        self.push_undo(temp_state);
+        let node = self.node(self.current);
+        let (Some(parent), Some(change)) = (node.parent, &node.change) else {
+            return;
+        };
+        canvas.revert_change(change);
+        self.node_mut(parent).redo_child = Some(self.current);
+        self.current = parent;
+    }
+
+    pub fn redo(&mut self, canvas: &mut Canvas) {
+        if canvas.has_pending_edits() {
+            self.push_change(canvas, UNRECORDED_NAME);
+        }
+
+        let Some(child) = self.node(self.current).redo_child else {
+            return;
+        };
+        if let Some(change) = &self.node(child).change {
+            canvas.apply_change(change);
+        }
+        self.current = child;
     }
 
-    /// Returns the state to undo to
-    pub fn undo(&mut self) -> &CanvasState {
-        // Undo stack is guaranteed to not be empty when called
-        let mut temp_state = self.undo_stk.pop_back().unwrap(); // has what we want to return
+    /// Moves the canvas to any state in the history, on any branch, by undoing back to
+    /// where the branches meet and redoing from there.
+    pub fn jump_to(&mut self, canvas: &mut Canvas, id: usize) {
+        if canvas.has_pending_edits() {
+            self.push_change(canvas, UNRECORDED_NAME);
+        }
+        if !self.nodes.contains_key(&id) {
+            return;
+        }
 
-        std::mem::swap(&mut temp_state, &mut self.current_state); // after this, temp_state will be holding what we want to push to redo, and current_state has what we want to return a copy of
+        let target_path = self.path_from_root(id);
+        while !target_path.contains(&self.current) {
+            self.undo(canvas);
+        }
+        let start = target_path.iter().position(|&n| n == self.current).unwrap();
+        for &next in &target_path[(start + 1)..] {
+            self.node_mut(self.current).redo_child = Some(next);
+            self.redo(canvas);
+        }
+    }
+}
 
-        self.push_redo(temp_state);
-        &self.current_state
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selection::Selection;
+
+    fn round_trip(pixels: Vec<Color32>) {
+        let compressed = CompressedPixels::encode(pixels.iter().copied());
+        assert_eq!(compressed.len(), pixels.len());
+        assert_eq!(compressed.is_empty(), pixels.is_empty());
+        assert_eq!(compressed.iter().collect::<Vec<_>>(), pixels);
+        assert_eq!(compressed.decode(), pixels);
     }
 
-    pub fn redo(&mut self) -> &CanvasState {
-        // Redo stack is guaranteed to not be empty when called
-        let mut temp_state = self.redo_stk.pop_back().unwrap(); // has what we want to return a copy of and move to current_state
+    #[test]
+    fn compressed_pixels_round_trip() {
+        let (a, b) = (Color32::RED, Color32::from_rgba_premultiplied(0, 0, 40, 40));
+        round_trip(Vec::new());
+        round_trip(vec![a]);
+        round_trip(vec![Color32::TRANSPARENT; 5000]);
+        round_trip(
+            [
+                vec![a; 3],
+                vec![b; 7],
+                vec![a; 1],
+                vec![Color32::TRANSPARENT; 4],
+            ]
+            .concat(),
+        );
+        round_trip((0..1000).map(|i| if i % 2 == 0 { a } else { b }).collect());
+    }
 
-        std::mem::swap(&mut temp_state, &mut self.current_state); // after this, temp_state holds what we want to push to undo, and need to return a copy of what is in current_state
+    #[test]
+    fn compressed_pixels_merge_runs() {
+        let compressed = CompressedPixels::encode(vec![Color32::RED; 1000]);
+        assert_eq!(compressed.0, vec![(1000, Color32::RED)]);
+    }
 
-        self.push_undo(temp_state);
-        &self.current_state
+    #[test]
+    fn compressed_selection_round_trip() {
+        let (width, height) = (13, 7);
+        let masks = [
+            vec![false; width * height],
+            vec![true; width * height],
+            (0..width * height).map(|i| i % 3 == 0).collect(),
+            Selection::rect(width, height, (2, 1), (9, 5))
+                .get_mask()
+                .to_vec(),
+        ];
+        for mask in masks {
+            let selection = Selection::from_mask(width, height, mask);
+            assert_eq!(CompressedSelection::encode(&selection).decode(), selection);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,373 @@
+use crate::model::{blend_over, Canvas};
+use egui::Color32;
+use image::error::{EncodingError, ImageError, ImageResult};
+use image::{ImageBuffer, ImageFormat, Rgba};
+use std::borrow::Cow;
+use std::io::Write;
+
+/// Nearest-neighbour upscale used until the user picks another one.
+pub const DEFAULT_EXPORT_SCALE: u32 = 10;
+pub const MAX_EXPORT_SCALE: u32 = 32;
+/// Most pixels one export may produce, counting every frame. The largest canvas at
+/// the largest scale would otherwise ask for tens of gigabytes.
+pub const MAX_EXPORT_PIXELS: u64 = 1 << 28;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ExportFormat {
+    #[default]
+    Png,
+    Bmp,
+    Gif,
+    Tga,
+    AnimatedGif,
+    Apng,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 6] = [
+        ExportFormat::Png,
+        ExportFormat::Bmp,
+        ExportFormat::Gif,
+        ExportFormat::Tga,
+        ExportFormat::AnimatedGif,
+        ExportFormat::Apng,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::Png => "PNG",
+            ExportFormat::Bmp => "BMP",
+            ExportFormat::Gif => "GIF",
+            ExportFormat::Tga => "TGA",
+            ExportFormat::AnimatedGif => "Animated GIF",
+            ExportFormat::Apng => "Animated PNG",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Bmp => "bmp",
+            ExportFormat::Gif => "gif",
+            ExportFormat::Tga => "tga",
+            ExportFormat::AnimatedGif => "gif",
+            ExportFormat::Apng => "png",
+        }
+    }
+
+    pub fn image_format(&self) -> ImageFormat {
+        match self {
+            ExportFormat::Png | ExportFormat::Apng => ImageFormat::Png,
+            ExportFormat::Bmp => ImageFormat::Bmp,
+            ExportFormat::Gif | ExportFormat::AnimatedGif => ImageFormat::Gif,
+            ExportFormat::Tga => ImageFormat::Tga,
+        }
+    }
+
+    /// Animated formats export every frame instead of just the active one.
+    pub fn is_animated(&self) -> bool {
+        matches!(self, ExportFormat::AnimatedGif | ExportFormat::Apng)
+    }
+
+    pub fn to_id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+}
+
+/// Export settings, kept per canvas so they are remembered between exports.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ExportOptions {
+    pub scale: u32,
+    pub format: ExportFormat,
+    pub include_hidden: bool,
+    pub fill_background: bool,
+    pub background: Color32,
+    pub crop_to_content: bool,
+    /// How many times an animation plays, 0 meaning forever.
+    pub loop_count: u16,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            scale: DEFAULT_EXPORT_SCALE,
+            format: ExportFormat::default(),
+            include_hidden: false,
+            fill_background: false,
+            background: Color32::WHITE,
+            crop_to_content: false,
+            loop_count: 0,
+        }
+    }
+}
+
+/// Flattens the active frame and applies the crop, background and scale from the
+/// options.
+pub fn render(
+    canvas: &Canvas,
+    options: &ExportOptions,
+) -> ImageResult<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    Ok(render_frames(canvas, options, &[canvas.get_active_frame()])?.remove(0))
+}
+
+/// Like `render`, for several frames at once. When cropping, every frame is cropped
+/// to the same rectangle so that the frames of an animation stay aligned.
+pub fn render_frames(
+    canvas: &Canvas,
+    options: &ExportOptions,
+    frames: &[usize],
+) -> ImageResult<Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>> {
+    let width = canvas.get_width();
+    let height = canvas.get_height();
+    let flattened: Vec<Vec<Color32>> = frames
+        .iter()
+        .map(|&frame_idx| canvas.flatten_frame(frame_idx, options.include_hidden))
+        .collect();
+
+    let full = (0, 0, width - 1, height - 1);
+    let (x1, y1, x2, y2) = if options.crop_to_content {
+        flattened
+            .iter()
+            .filter_map(|pixels| content_bounds(pixels, width, height))
+            .reduce(|(ax1, ay1, ax2, ay2), (bx1, by1, bx2, by2)| {
+                (ax1.min(bx1), ay1.min(by1), ax2.max(bx2), ay2.max(by2))
+            })
+            .unwrap_or(full)
+    } else {
+        full
+    };
+
+    let scale = options.scale.clamp(1, MAX_EXPORT_SCALE);
+    let out_width = (x2 - x1 + 1) as u32 * scale;
+    let out_height = (y2 - y1 + 1) as u32 * scale;
+    check_export_size(
+        out_width as u64 * out_height as u64 * frames.len() as u64,
+        options.format.image_format(),
+    )?;
+
+    Ok(flattened
+        .iter()
+        .map(|pixels| {
+            ImageBuffer::from_fn(out_width, out_height, |x, y| {
+                let (px, py) = (x1 + (x / scale) as usize, y1 + (y / scale) as usize);
+                let mut color = pixels[px + py * width];
+                if options.fill_background {
+                    color = blend_over(options.background, color);
+                }
+                Rgba(color.to_srgba_unmultiplied())
+            })
+        })
+        .collect())
+}
+
+/// Fails instead of allocating when an export would have more than
+/// `MAX_EXPORT_PIXELS` pixels.
+pub fn check_export_size(pixels: u64, format: ImageFormat) -> ImageResult<()> {
+    if pixels > MAX_EXPORT_PIXELS {
+        return Err(ImageError::Encoding(EncodingError::new(
+            format.into(),
+            format!(
+                "The export would have {} pixels, more than the {} allowed. Try a smaller scale",
+                pixels, MAX_EXPORT_PIXELS
+            ),
+        )));
+    }
+    Ok(())
+}
+
+/// Writes every frame of the canvas as an animated GIF. GIF only has 256 colors and
+/// on/off transparency, so frames with more colors are quantized and pixels that are
+/// less than half opaque become transparent.
+pub fn write_gif<W: Write>(writer: W, canvas: &Canvas, options: &ExportOptions) -> ImageResult<()> {
+    let frames: Vec<usize> = (0..canvas.get_num_frames()).collect();
+    let images = render_frames(canvas, options, &frames)?;
+    let (width, height) = images[0].dimensions();
+    let (width, height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(ImageError::Encoding(EncodingError::new(
+                ImageFormat::Gif.into(),
+                "Image is too large for a GIF",
+            )))
+        }
+    };
+
+    let mut encoder = gif::Encoder::new(writer, width, height, &[]).map_err(gif_error)?;
+    match options.loop_count {
+        0 => encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(gif_error)?,
+        // The loop extension counts repeats after the first play, a single play has none
+        1 => {}
+        n => encoder
+            .set_repeat(gif::Repeat::Finite(n - 1))
+            .map_err(gif_error)?,
+    }
+
+    for (frame_idx, image) in frames.into_iter().zip(images) {
+        let (indices, palette, transparent) = quantize(image.as_raw());
+        // GIF delays are in hundredths of a second
+        let delay = canvas.get_frame_duration(frame_idx).div_ceil(10);
+        let frame = gif::Frame {
+            width,
+            height,
+            delay: delay.min(u16::MAX as u32) as u16,
+            dispose: gif::DisposalMethod::Background,
+            transparent,
+            palette: Some(palette),
+            buffer: Cow::Owned(indices),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).map_err(gif_error)?;
+    }
+    Ok(())
+}
+
+/// Writes every frame of the canvas as an animated PNG, keeping full alpha.
+pub fn write_apng<W: Write>(
+    writer: W,
+    canvas: &Canvas,
+    options: &ExportOptions,
+) -> ImageResult<()> {
+    let frames: Vec<usize> = (0..canvas.get_num_frames()).collect();
+    let images = render_frames(canvas, options, &frames)?;
+    let (width, height) = images[0].dimensions();
+
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(frames.len() as u32, options.loop_count as u32)
+        .map_err(png_error)?;
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    for (frame_idx, image) in frames.into_iter().zip(images) {
+        let delay = canvas.get_frame_duration(frame_idx).min(u16::MAX as u32) as u16;
+        writer.set_frame_delay(delay, 1000).map_err(png_error)?;
+        writer.write_image_data(image.as_raw()).map_err(png_error)?;
+    }
+    writer.finish().map_err(png_error)
+}
+
+/// Turns RGBA bytes into palette indices, an RGB palette and the transparent index.
+/// Images with at most 255 opaque colors keep their exact colors, which is nearly
+/// always the case for pixel art, anything else goes through NeuQuant.
+fn quantize(rgba: &[u8]) -> (Vec<u8>, Vec<u8>, Option<u8>) {
+    let is_transparent = |p: &[u8]| p[3] < 128;
+
+    let mut colors: Vec<[u8; 3]> = rgba
+        .chunks_exact(4)
+        .filter(|p| !is_transparent(p))
+        .map(|p| [p[0], p[1], p[2]])
+        .collect();
+    colors.sort_unstable();
+    colors.dedup();
+
+    let has_transparency = rgba.chunks_exact(4).any(is_transparent);
+
+    if colors.len() <= 255 {
+        let transparent = colors.len() as u8;
+        let indices = rgba
+            .chunks_exact(4)
+            .map(|p| match is_transparent(p) {
+                true => transparent,
+                false => colors.binary_search(&[p[0], p[1], p[2]]).unwrap() as u8,
+            })
+            .collect();
+        let mut palette: Vec<u8> = colors.into_iter().flatten().collect();
+        palette.extend_from_slice(&[0, 0, 0]);
+        return (indices, palette, has_transparency.then_some(transparent));
+    }
+
+    // Leave one slot free for the transparent color
+    let quant = color_quant::NeuQuant::new(10, 255, rgba);
+    let transparent = 255;
+    let indices = rgba
+        .chunks_exact(4)
+        .map(|p| match is_transparent(p) {
+            true => transparent,
+            false => quant.index_of(&[p[0], p[1], p[2], 255]) as u8,
+        })
+        .collect();
+    let mut palette: Vec<u8> = quant
+        .color_map_rgba()
+        .chunks_exact(4)
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect();
+    palette.resize(256 * 3, 0);
+    (indices, palette, has_transparency.then_some(transparent))
+}
+
+fn gif_error(e: gif::EncodingError) -> ImageError {
+    match e {
+        gif::EncodingError::Io(e) => ImageError::IoError(e),
+        e => ImageError::Encoding(EncodingError::new(ImageFormat::Gif.into(), e)),
+    }
+}
+
+fn png_error(e: png::EncodingError) -> ImageError {
+    match e {
+        png::EncodingError::IoError(e) => ImageError::IoError(e),
+        e => ImageError::Encoding(EncodingError::new(ImageFormat::Png.into(), e)),
+    }
+}
+
+/// Smallest rectangle `(x1, y1, x2, y2)` holding every non transparent pixel.
+pub fn content_bounds(
+    pixels: &[Color32],
+    width: usize,
+    height: usize,
+) -> Option<(usize, usize, usize, usize)> {
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for y in 0..height {
+        for x in 0..width {
+            if pixels[x + y * width].a() == 0 {
+                continue;
+            }
+            bounds = Some(match bounds {
+                Some((x1, y1, x2, y2)) => (x1.min(x), y1.min(y), x2.max(x), y2.max(y)),
+                None => (x, y, x, y),
+            });
+        }
+    }
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sprite_sheet::{self, SpriteSheetOptions};
+
+    #[test]
+    fn oversized_exports_are_an_error() {
+        // 1024 * 32 squared is four times the budget
+        let mut canvas = Canvas::new(1024, 1024);
+        canvas.add_frame();
+        let options = ExportOptions {
+            scale: MAX_EXPORT_SCALE,
+            ..ExportOptions::default()
+        };
+        assert!(render(&canvas, &options).is_err());
+        assert!(write_apng(std::io::sink(), &canvas, &options).is_err());
+
+        let sheet_options = SpriteSheetOptions {
+            scale: MAX_EXPORT_SCALE,
+            ..SpriteSheetOptions::default()
+        };
+        assert!(sprite_sheet::build(&canvas, &sheet_options, "sheet", "sheet.png").is_err());
+    }
+
+    #[test]
+    fn exports_within_the_budget_render() {
+        let canvas = Canvas::new(16, 8);
+        let options = ExportOptions {
+            scale: 3,
+            ..ExportOptions::default()
+        };
+        assert_eq!(render(&canvas, &options).unwrap().dimensions(), (48, 24));
+    }
+}
@@ -1,8 +1,105 @@
-use image::{DynamicImage, ImageBuffer, Rgba};
-use rfd::FileDialog;
+use crate::export::{self, ExportFormat, DEFAULT_EXPORT_SCALE};
+use crate::model::{Canvas, MAX_CANVAS_SIZE};
+use crate::project::{self, Project, PROJECT_EXTENSION};
+use crate::sprite_sheet::{self, SpriteSheetOptions};
+use egui::Color32;
+use image::{ImageBuffer, ImageError, ImageFormat, Rgba};
+use rfd::{FileDialog, MessageButtons, MessageDialog, MessageDialogResult};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Anything that can go wrong while reading or writing a file, along with the file.
+#[derive(Debug)]
+pub enum FileError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Image {
+        path: PathBuf,
+        source: ImageError,
+    },
+    /// An image or project bigger than the largest canvas.
+    TooLarge {
+        path: PathBuf,
+        width: usize,
+        height: usize,
+    },
+}
+
+impl FileError {
+    fn io(path: &Path, source: io::Error) -> Self {
+        FileError::Io {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    /// Fails with `TooLarge` if a `width * height` canvas would be too big to edit.
+    fn check_size(path: &Path, width: usize, height: usize) -> Result<(), Self> {
+        if width > MAX_CANVAS_SIZE || height > MAX_CANVAS_SIZE {
+            return Err(FileError::TooLarge {
+                path: path.to_path_buf(),
+                width,
+                height,
+            });
+        }
+        Ok(())
+    }
+
+    fn image(path: &Path, source: ImageError) -> Self {
+        match source {
+            // Keep plain I/O failures (permissions, full disk...) in one place.
+            ImageError::IoError(source) => Self::io(path, source),
+            source => FileError::Image {
+                path: path.to_path_buf(),
+                source,
+            },
+        }
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            FileError::Image { path, source } => write!(f, "{}: {}", path.display(), source),
+            FileError::TooLarge {
+                path,
+                width,
+                height,
+            } => write!(
+                f,
+                "{}: {}x{} is larger than the largest canvas, {}x{}",
+                path.display(),
+                width,
+                height,
+                MAX_CANVAS_SIZE,
+                MAX_CANVAS_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileError::Io { source, .. } => Some(source),
+            FileError::Image { source, .. } => Some(source),
+            FileError::TooLarge { .. } => None,
+        }
+    }
+}
+
+pub enum OpenedFile {
+    Image(ImageBuffer<Rgba<u8>, Vec<u8>>),
+    Project(Project),
+}
 
 pub struct FileInteractions {
-    pub show_save_dialog: bool,
+    last_directory: Option<PathBuf>,
 }
 
 impl Default for FileInteractions {
@@ -14,27 +111,221 @@ impl Default for FileInteractions {
 impl FileInteractions {
     pub fn new() -> FileInteractions {
         FileInteractions {
-            show_save_dialog: false,
+            last_directory: None,
         }
     }
 
-    pub fn save_file(&mut self, rgba_buffer: ImageBuffer<Rgba<u8>, Vec<u8>>) {
-        let img = DynamicImage::from(rgba_buffer);
-        let scaled_img = img.resize(
-            img.width() * 10,
-            img.height() * 10,
-            image::imageops::FilterType::Nearest,
-        );
+    /// File dialog starting in the directory of the last opened or saved file.
+    fn dialog(&self) -> FileDialog {
+        match &self.last_directory {
+            Some(dir) => FileDialog::new().set_directory(dir),
+            None => FileDialog::new(),
+        }
+    }
+
+    fn remember_directory(&mut self, path: &Path) {
+        self.last_directory = path.parent().map(Path::to_path_buf);
+    }
 
-        if let Some(mut path) = FileDialog::new().save_file() {
-            path.set_extension("png");
+    /// Exports the flattened canvas using the export options stored in the canvas.
+    /// Returns the path written to, or `None` if the user cancelled the dialog.
+    pub fn export_file(&mut self, canvas: &Canvas) -> Result<Option<PathBuf>, FileError> {
+        let options = canvas.get_export_options();
+        let format = options.format;
+
+        let Some(mut path) = self
+            .dialog()
+            .add_filter(format.name(), &[format.extension()])
+            .save_file()
+        else {
+            return Ok(None);
+        };
+        path.set_extension(format.extension());
+        self.remember_directory(&path);
+
+        Self::write_export_file(&path, canvas).map_err(|e| FileError::image(&path, e))?;
+        Ok(Some(path))
+    }
 
-            scaled_img
-                .save(path)
-                .unwrap();
+    /// Packs every frame into a PNG and writes the JSON atlas describing it next to
+    /// it, with the same name. Returns the path of the PNG, or `None` if the user
+    /// cancelled the dialog.
+    pub fn export_sprite_sheet(
+        &mut self,
+        canvas: &Canvas,
+        options: &SpriteSheetOptions,
+    ) -> Result<Option<PathBuf>, FileError> {
+        let Some(mut path) = self.dialog().add_filter("PNG", &["png"]).save_file() else {
+            return Ok(None);
+        };
+        path.set_extension("png");
+        self.remember_directory(&path);
+
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let image_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let sheet = sprite_sheet::build(canvas, options, &name, &image_name)
+            .map_err(|e| FileError::image(&path, e))?;
+        sheet
+            .image
+            .save_with_format(&path, ImageFormat::Png)
+            .map_err(|e| FileError::image(&path, e))?;
+
+        let atlas_path = path.with_extension("json");
+        Self::write_atlas_file(&atlas_path, &sheet.atlas, options)
+            .map_err(|e| FileError::io(&atlas_path, e))?;
+        Ok(Some(path))
+    }
+
+    /// Asks the user for an image or a project and decodes it. Images that look like
+    /// they were exported at the default scale can be scaled back down to their
+    /// original pixel size, if the user agrees. Returns `None` if the user cancelled
+    /// the dialog.
+    pub fn open_file(&mut self) -> Result<Option<OpenedFile>, FileError> {
+        let Some(path) = self
+            .dialog()
+            .add_filter(
+                "Ruxel Project or Image",
+                &[PROJECT_EXTENSION, "png", "gif", "bmp"],
+            )
+            .add_filter("Ruxel Project", &[PROJECT_EXTENSION])
+            .add_filter("Image", &["png", "gif", "bmp"])
+            .pick_file()
+        else {
+            return Ok(None);
+        };
+        self.remember_directory(&path);
+
+        if path.extension().is_some_and(|ext| ext == PROJECT_EXTENSION) {
+            let project = Self::read_project_file(&path).map_err(|e| FileError::io(&path, e))?;
+            FileError::check_size(&path, project.width, project.height)?;
+            return Ok(Some(OpenedFile::Project(project)));
+        }
+
+        // Exports bigger than the largest canvas may still scale down to fit, but
+        // nothing with more pixels than it is decoded to find out
+        let (width, height) = image::io::Reader::open(&path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|e| FileError::io(&path, e))?
+            .into_dimensions()
+            .map_err(|e| FileError::image(&path, e))?;
+        if width as u64 * height as u64 > (MAX_CANVAS_SIZE * MAX_CANVAS_SIZE) as u64 {
+            return Err(FileError::TooLarge {
+                path,
+                width: width as usize,
+                height: height as usize,
+            });
         }
-        self.show_save_dialog = false;
+
+        let rgba_buffer = image::open(&path)
+            .map_err(|e| FileError::image(&path, e))?
+            .to_rgba8();
+        let rgba_buffer = match Self::unscale(&rgba_buffer, DEFAULT_EXPORT_SCALE) {
+            Some(unscaled) if Self::confirm_unscale(&unscaled, DEFAULT_EXPORT_SCALE) => unscaled,
+            _ => rgba_buffer,
+        };
+        let (width, height) = rgba_buffer.dimensions();
+        FileError::check_size(&path, width as usize, height as usize)?;
+        Ok(Some(OpenedFile::Image(rgba_buffer)))
     }
-}
 
+    /// Saves the canvas with all of its layers, plus the palette, as a project.
+    /// Returns the path written to, or `None` if the user cancelled the dialog.
+    pub fn save_project(
+        &mut self,
+        canvas: &Canvas,
+        palette: &[Color32],
+    ) -> Result<Option<PathBuf>, FileError> {
+        let Some(mut path) = self
+            .dialog()
+            .add_filter("Ruxel Project", &[PROJECT_EXTENSION])
+            .save_file()
+        else {
+            return Ok(None);
+        };
+        path.set_extension(PROJECT_EXTENSION);
+        self.remember_directory(&path);
+
+        Self::write_project_file(&path, canvas, palette).map_err(|e| FileError::io(&path, e))?;
+        Ok(Some(path))
+    }
 
+    fn write_export_file(path: &Path, canvas: &Canvas) -> image::ImageResult<()> {
+        let options = canvas.get_export_options();
+        match options.format {
+            ExportFormat::AnimatedGif | ExportFormat::Apng => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                if options.format == ExportFormat::AnimatedGif {
+                    export::write_gif(&mut writer, canvas, options)?;
+                } else {
+                    export::write_apng(&mut writer, canvas, options)?;
+                }
+                writer.flush()?;
+                Ok(())
+            }
+            format => {
+                export::render(canvas, options)?.save_with_format(path, format.image_format())
+            }
+        }
+    }
+
+    fn write_atlas_file(
+        path: &Path,
+        atlas: &sprite_sheet::Atlas,
+        options: &SpriteSheetOptions,
+    ) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        sprite_sheet::write_atlas(&mut writer, atlas, options.atlas_format)?;
+        writer.flush()
+    }
+
+    fn read_project_file(path: &Path) -> io::Result<Project> {
+        let mut reader = BufReader::new(File::open(path)?);
+        project::read_project(&mut reader)
+    }
+
+    fn write_project_file(path: &Path, canvas: &Canvas, palette: &[Color32]) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        project::write_project(&mut writer, canvas, palette)?;
+        writer.flush()
+    }
+
+    /// Plain images, like a single color, can look upscaled too, so the user decides.
+    fn confirm_unscale(unscaled: &ImageBuffer<Rgba<u8>, Vec<u8>>, scale: u32) -> bool {
+        let (width, height) = unscaled.dimensions();
+        let description = format!(
+            "This image looks like it was exported at {}x. Open it at its original size, {}x{}?",
+            scale, width, height
+        );
+        let result = MessageDialog::new()
+            .set_title("Open image")
+            .set_description(description)
+            .set_buttons(MessageButtons::YesNo)
+            .show();
+        result == MessageDialogResult::Yes
+    }
+
+    /// Returns the image shrunk by `scale` if every `scale`x`scale` block of it is a
+    /// single color, which is what a nearest-neighbour upscale leaves behind.
+    fn unscale(
+        rgba_buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+        scale: u32,
+    ) -> Option<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let (width, height) = rgba_buffer.dimensions();
+        if scale < 2 || width % scale != 0 || height % scale != 0 {
+            return None;
+        }
+
+        let uniform = rgba_buffer
+            .enumerate_pixels()
+            .all(|(x, y, p)| p == rgba_buffer.get_pixel(x - x % scale, y - y % scale));
+        if !uniform {
+            return None;
+        }
+
+        Some(ImageBuffer::from_fn(
+            width / scale,
+            height / scale,
+            |x, y| *rgba_buffer.get_pixel(x * scale, y * scale),
+        ))
+    }
+}
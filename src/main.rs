@@ -2,6 +2,12 @@ pub mod model;
 pub mod ui;
 pub mod file_interactions;
 pub mod change_manager;
+pub mod export;
+pub mod project;
+pub mod sprite_sheet;
+pub mod selection;
+pub mod rotsprite;
+pub mod raster;
 use ui::Ruxel;
 
 fn main() {
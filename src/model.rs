@@ -1,7 +1,14 @@
-use crate::change_manager::CanvasState;
+use crate::change_manager::{
+    CanvasMeta, CanvasState, Change, CompressedPixels, CompressedSelection, Edit, PixelSpan,
+};
+use crate::export::ExportOptions;
+use crate::raster;
+use crate::rotsprite;
+use crate::selection::{Clipboard, Selection, SelectionMode, Transform};
 use egui::{epaint::RectShape, *};
 use image::{ImageBuffer, Rgba};
 use rayon::prelude::*;
+use std::collections::HashMap;
 
 #[derive(Default)]
 pub struct Camera {
@@ -47,6 +54,201 @@ impl Camera {
     }
 }
 
+/// Settings for drawing neighbouring frames underneath the active one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct OnionSkin {
+    pub enabled: bool,
+    pub previous: usize,
+    pub next: usize,
+    pub opacity: f32,
+}
+
+impl Default for OnionSkin {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            previous: 1,
+            next: 1,
+            opacity: 0.3,
+        }
+    }
+}
+
+/// Settings for drawing seamless tiles.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Tiling {
+    /// Painting that goes over an edge of the canvas comes back in on the other side.
+    pub wrap: bool,
+    /// Draw copies of the canvas all around it, to see how it tiles.
+    pub preview: bool,
+}
+
+/// How the fill tool decides which pixels to fill.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FillOptions {
+    /// How far a color may be from the clicked one and still be filled, in percent of
+    /// the largest possible RGBA distance.
+    pub tolerance: u8,
+    /// Only fill pixels connected to the clicked one, instead of every matching pixel.
+    pub contiguous: bool,
+    /// Let fills spread through diagonal neighbours as well.
+    pub diagonal: bool,
+    /// Compare against the visible image instead of just the active layer.
+    pub sample_merged: bool,
+}
+
+impl Default for FillOptions {
+    fn default() -> Self {
+        Self {
+            tolerance: 0,
+            contiguous: true,
+            diagonal: false,
+            sample_merged: false,
+        }
+    }
+}
+
+impl FillOptions {
+    pub fn matches(&self, target: Color32, color: Color32) -> bool {
+        let distance_sq: u32 = target
+            .to_array()
+            .iter()
+            .zip(color.to_array())
+            .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+            .sum();
+        // The largest distance is between transparent and opaque white, 2 * 255
+        let max_distance = 2 * 255 * self.tolerance.min(100) as u32 / 100;
+        distance_sq <= max_distance * max_distance
+    }
+}
+
+/// Whether shape tools draw just the outline of a shape, fill it in, or both in two
+/// colors.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum ShapeMode {
+    #[default]
+    Outline,
+    Filled,
+    OutlineAndFill,
+}
+
+impl ShapeMode {
+    pub const ALL: [ShapeMode; 3] = [
+        ShapeMode::Outline,
+        ShapeMode::Filled,
+        ShapeMode::OutlineAndFill,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShapeMode::Outline => "Outline",
+            ShapeMode::Filled => "Filled",
+            ShapeMode::OutlineAndFill => "Outline and fill",
+        }
+    }
+}
+
+/// How the rectangle and ellipse tools draw their shapes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ShapeStyle {
+    pub mode: ShapeMode,
+    /// Thickness of the outline, in pixels.
+    pub width: usize,
+    /// Radius of the rounded corners of rectangles, 0 for square corners.
+    pub corner_radius: usize,
+    pub color: Color32,
+    /// Color of the inside with `ShapeMode::OutlineAndFill`.
+    pub fill_color: Color32,
+}
+
+impl ShapeStyle {
+    /// Colors the pixels of a shape, given its outline and all of its pixels.
+    fn paint(
+        &self,
+        outline: impl FnOnce() -> Vec<(isize, isize)>,
+        filled: impl FnOnce() -> Vec<(isize, isize)>,
+    ) -> Vec<((isize, isize), Color32)> {
+        let with = |pixels: Vec<(isize, isize)>, color| {
+            pixels.into_iter().map(move |pixel| (pixel, color))
+        };
+        match self.mode {
+            ShapeMode::Outline => with(outline(), self.color).collect(),
+            ShapeMode::Filled => with(filled(), self.color).collect(),
+            ShapeMode::OutlineAndFill => {
+                let outline = outline();
+                let inside = raster::subtract(filled(), &outline);
+                with(outline, self.color)
+                    .chain(with(inside, self.fill_color))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Which way painting is mirrored. Horizontal symmetry mirrors left and right across a
+/// vertical axis, vertical symmetry mirrors top and bottom across a horizontal one.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum SymmetryMode {
+    #[default]
+    None,
+    Horizontal,
+    Vertical,
+    Both,
+}
+
+impl SymmetryMode {
+    pub const ALL: [SymmetryMode; 4] = [
+        SymmetryMode::None,
+        SymmetryMode::Horizontal,
+        SymmetryMode::Vertical,
+        SymmetryMode::Both,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SymmetryMode::None => "None",
+            SymmetryMode::Horizontal => "Horizontal",
+            SymmetryMode::Vertical => "Vertical",
+            SymmetryMode::Both => "Both",
+        }
+    }
+}
+
+/// Mirroring of what the painting tools paint.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Symmetry {
+    pub mode: SymmetryMode,
+    /// Position of the vertical axis in half pixels from the left edge of the canvas.
+    /// Even positions lie between two pixels, odd ones run through the middle of one.
+    pub axis_x: isize,
+    /// Position of the horizontal axis in half pixels from the top edge of the canvas.
+    pub axis_y: isize,
+}
+
+impl Symmetry {
+    fn mirrors_x(&self) -> bool {
+        matches!(self.mode, SymmetryMode::Horizontal | SymmetryMode::Both)
+    }
+
+    fn mirrors_y(&self) -> bool {
+        matches!(self.mode, SymmetryMode::Vertical | SymmetryMode::Both)
+    }
+
+    /// A pixel along with its mirror images, each only once.
+    fn images(&self, (x, y): (isize, isize)) -> Vec<(isize, isize)> {
+        let (mx, my) = (self.axis_x - 1 - x, self.axis_y - 1 - y);
+        let mut images = match self.mode {
+            SymmetryMode::None => vec![(x, y)],
+            SymmetryMode::Horizontal => vec![(x, y), (mx, y)],
+            SymmetryMode::Vertical => vec![(x, y), (x, my)],
+            SymmetryMode::Both => vec![(x, y), (mx, y), (x, my), (mx, my)],
+        };
+        images.sort_unstable();
+        images.dedup();
+        images
+    }
+}
+
 pub struct Canvas {
     width: usize,
     height: usize,
@@ -55,54 +257,145 @@ pub struct Canvas {
     layer_names: Vec<String>,
     layer_name_cnt: usize,
     layers_to_show: Vec<bool>,
+    frames: usize,
+    active_frame: usize,
+    frame_durations: Vec<u32>,
     alpha_ratio: usize,
     pixels: Vec<Color32>,
-    squares: Vec<Shape>,
+    /// The visible layers of the active frame composited together, as shown on screen.
+    image: Vec<Color32>,
+    /// Region of `image` that changed since it was last uploaded, as `(x1, y1, x2, y2)`.
+    dirty: Option<(usize, usize, usize, usize)>,
+    onion_skin: OnionSkin,
+    /// The frames around the active one, tinted and composited together.
+    onion_image: Vec<Color32>,
+    onion_dirty: bool,
+    textures: Option<CanvasTextures>,
+    /// Metadata as of the last change taken by `take_change`.
+    recorded_meta: CanvasMeta,
+    /// Copies of the chunks of `pixels` written since the last change was taken, from
+    /// before they were first written.
+    touched_chunks: HashMap<usize, Vec<Color32>>,
+    /// Layer and frame edits made since the last change was taken.
+    edits: Vec<Edit>,
+    /// Pixels the tools are limited to, every pixel when `None`.
+    selection: Option<Selection>,
+    /// Selection as of the last change taken by `take_change`.
+    recorded_selection: Option<Selection>,
+    floating: Option<FloatingSelection>,
+    brush_stroke: Option<BrushStroke>,
+    symmetry: Symmetry,
+    tiling: Tiling,
+    shape_preview: Option<ShapePreview>,
     camera: Camera,
     stroke: Stroke,
+    export_options: ExportOptions,
+}
+
+/// Selected pixels lifted off the active layer while they are being moved.
+struct FloatingSelection {
+    pixels: Clipboard,
+    /// The selection from before the move.
+    selection: Selection,
+    /// The active cel with the selected pixels cut out, to restore whatever the
+    /// floating pixels no longer cover.
+    base: Vec<Color32>,
+    /// Pixel the move was started from.
+    start: (isize, isize),
+    offset: (isize, isize),
+}
+
+/// The stroke being drawn with the brush.
+struct BrushStroke {
+    /// Pixel the brush was last put down at.
+    last: (isize, isize),
+    /// Pixels of a pixel-perfect stroke so far, each with the mirror images painted
+    /// for it and the colors they had before.
+    path: Vec<StrokePixel>,
+}
+
+type StrokePixel = ((isize, isize), Vec<((isize, isize), Option<Color32>)>);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ShapeKind {
+    Rect,
+    Ellipse,
+}
+
+/// Everything the pixels of a shape preview depend on.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct ShapePreviewKey {
+    kind: ShapeKind,
+    bounds: (isize, isize, isize, isize),
+    style: ShapeStyle,
+    symmetry: Symmetry,
+    tiling: Tiling,
+}
+
+/// The last shape previewed, see `Canvas::shape_preview`.
+struct ShapePreview {
+    key: ShapePreviewKey,
+    /// Columns `(x, y1, y2)` of the pixels of the shape.
+    columns: Vec<(isize, isize, isize)>,
+}
+
+/// The GPU side of the canvas, created the first time the canvas is drawn.
+struct CanvasTextures {
+    image: TextureHandle,
+    onion: TextureHandle,
+    checkerboard: TextureHandle,
 }
 
 pub const DEFAULT_SIZE: usize = 32;
+pub const MAX_CANVAS_SIZE: usize = 4096;
 pub const SCROLL_SENSITIVITY: f32 = 0.8;
+const MIN_SQUARE_SIZE: f32 = 0.125;
+const MAX_SQUARE_SIZE: f32 = 100.0;
+/// Milliseconds a new frame is shown for during playback and in animated exports.
+pub const DEFAULT_FRAME_DURATION: u32 = 100;
 const HIGHLIGHT_COLOR: Color32 = Color32::from_rgba_premultiplied(0, 0, 0, 127);
+const ONION_PREVIOUS_TINT: Color32 = Color32::from_rgb(255, 64, 64);
+const ONION_NEXT_TINT: Color32 = Color32::from_rgb(64, 128, 255);
+/// Pixels are copied this many at a time before they are first written, to find out
+/// what a change did.
+const HISTORY_CHUNK_SIZE: usize = 4096;
+/// Unchanged pixels between two changed ones are stored in the history anyway when
+/// there are this few of them, which is smaller than starting a new span.
+const HISTORY_SPAN_GAP: usize = 16;
+/// Length in screen points of the dashes and gaps of the selection outline.
+const MARCHING_ANTS_DASH: f32 = 4.0;
+/// How fast the selection outline crawls, in screen points per second.
+const MARCHING_ANTS_SPEED: f64 = 8.0;
+/// Seconds between the steps the selection outline crawls in, a quarter of a dash each,
+/// so it only needs redrawing that often.
+pub const MARCHING_ANTS_STEP: f64 = MARCHING_ANTS_DASH as f64 / 4.0 / MARCHING_ANTS_SPEED;
+/// Side in screen points of the handles for scaling a selection.
+const SCALE_HANDLE_SIZE: f32 = 8.0;
+/// Side in screen points of the handles for moving the symmetry axes.
+const SYMMETRY_HANDLE_SIZE: f32 = 10.0;
+const SYMMETRY_AXIS_COLOR: Color32 = Color32::from_rgb(64, 160, 255);
+/// Which sides each scale handle moves, -1 for the left or top side and 1 for the right
+/// or bottom one.
+const SCALE_HANDLES: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+];
 
 impl Default for Canvas {
     fn default() -> Self {
-        Self {
-            width: DEFAULT_SIZE,
-            height: DEFAULT_SIZE,
-            layers: 1,
-            active_layer: 0,
-            layer_names: vec![String::from("0"); 1],
-            layer_name_cnt: 1,
-            layers_to_show: vec![true; 1],
-            alpha_ratio: 8,
-            pixels: vec![Color32::TRANSPARENT; DEFAULT_SIZE * DEFAULT_SIZE],
-            squares: vec![
-                Shape::from(RectShape::new(
-                    Rect::from_min_size(Pos2::default(), Vec2::default()),
-                    Rounding::default(),
-                    Color32::TRANSPARENT,
-                    Stroke::NONE,
-                ));
-                DEFAULT_SIZE * DEFAULT_SIZE * 2
-            ],
-            camera: Camera {
-                square_size: 10.0,
-                pixel_center: Pos2 {
-                    x: (DEFAULT_SIZE / 2) as f32,
-                    y: (DEFAULT_SIZE / 2) as f32,
-                },
-                screen_center: Pos2::default(),
-            },
-            stroke: Stroke::NONE,
-        }
+        Self::new(DEFAULT_SIZE, DEFAULT_SIZE)
     }
 }
 
 impl Canvas {
     pub fn new(width: usize, height: usize) -> Self {
-        Self {
+        let mut canvas = Self {
             width,
             height,
             layers: 1,
@@ -110,17 +403,31 @@ impl Canvas {
             layer_names: vec![String::from("0"); 1],
             layer_name_cnt: 1,
             layers_to_show: vec![true; 1],
+            frames: 1,
+            active_frame: 0,
+            frame_durations: vec![DEFAULT_FRAME_DURATION; 1],
             alpha_ratio: 8,
             pixels: vec![Color32::TRANSPARENT; width * height],
-            squares: vec![
-                Shape::from(RectShape::new(
-                    Rect::from_min_size(Pos2::default(), Vec2::default()),
-                    Rounding::default(),
-                    Color32::TRANSPARENT,
-                    Stroke::NONE,
-                ));
-                width * height * 2
-            ],
+            image: vec![Color32::TRANSPARENT; width * height],
+            dirty: None,
+            onion_skin: OnionSkin::default(),
+            onion_image: vec![Color32::TRANSPARENT; width * height],
+            onion_dirty: false,
+            textures: None,
+            recorded_meta: CanvasMeta::default(),
+            touched_chunks: HashMap::new(),
+            edits: Vec::new(),
+            selection: None,
+            recorded_selection: None,
+            floating: None,
+            brush_stroke: None,
+            symmetry: Symmetry {
+                mode: SymmetryMode::None,
+                axis_x: width as isize,
+                axis_y: height as isize,
+            },
+            tiling: Tiling::default(),
+            shape_preview: None,
             camera: Camera {
                 square_size: 10.0,
                 pixel_center: Pos2 {
@@ -130,104 +437,85 @@ impl Canvas {
                 screen_center: Pos2::default(),
             },
             stroke: Stroke::NONE,
-        }
+            export_options: ExportOptions::default(),
+        };
+        canvas.recorded_meta = canvas.meta();
+        canvas
     }
 
-    #[allow(dead_code)]
-    fn get_layer(&self, layer_idx: usize) -> Option<Vec<Color32>> {
-        if layer_idx >= self.layers {
-            let layer_size = self.width * self.height;
-            Some(self.pixels[(layer_size * layer_idx)..(layer_size * (layer_idx + 1))].to_vec())
-        } else {
-            None
-        }
+    /// Frames are stored one after the other, each holding a cel for every layer.
+    fn frame_offset(&self, frame_idx: usize) -> usize {
+        self.width * self.height * self.layers * frame_idx
     }
 
-    fn get_pixel(&self, x: usize, y: usize, layer_idx: usize) -> Option<&Color32> {
-        if layer_idx < self.layers && x < self.width && y < self.height {
+    #[allow(dead_code)]
+    fn get_layer(&self, layer_idx: usize) -> Option<Vec<Color32>> {
+        if layer_idx < self.layers {
             let layer_size = self.width * self.height;
-            self.pixels
-                .get(x + (y * self.width) + (layer_size * layer_idx))
+            let start = self.frame_offset(self.active_frame) + layer_size * layer_idx;
+            Some(self.pixels[start..(start + layer_size)].to_vec())
         } else {
             None
         }
     }
 
-    fn get_pixel_mut(&mut self, x: usize, y: usize, layer_idx: usize) -> Option<&mut Color32> {
-        if layer_idx < self.layers && x < self.width && y < self.height {
-            let layer_size = self.width * self.height;
-            self.pixels
-                .get_mut(x + (y * self.width) + (layer_size * layer_idx))
-        } else {
-            None
-        }
+    /// The pixels of one layer in one frame, `width * height` long.
+    pub fn get_cel(&self, layer_idx: usize, frame_idx: usize) -> &[Color32] {
+        let layer_size = self.width * self.height;
+        let start = self.frame_offset(frame_idx) + layer_size * layer_idx;
+        &self.pixels[start..(start + layer_size)]
     }
 
-    #[allow(dead_code)]
-    fn get_square(&self, x: usize, y: usize, layer_idx: usize) -> Option<&Shape> {
+    fn get_pixel(&self, x: usize, y: usize, layer_idx: usize) -> Option<&Color32> {
         if layer_idx < self.layers && x < self.width && y < self.height {
             let layer_size = self.width * self.height;
-            self.squares
-                .get(x + (y * self.width) + (layer_size * (layer_idx + 1)))
+            self.pixels.get(
+                x + (y * self.width)
+                    + (layer_size * layer_idx)
+                    + self.frame_offset(self.active_frame),
+            )
         } else {
             None
         }
     }
 
-    fn get_square_mut(&mut self, x: usize, y: usize, layer_idx: usize) -> Option<&mut Shape> {
+    fn get_pixel_mut(&mut self, x: usize, y: usize, layer_idx: usize) -> Option<&mut Color32> {
         if layer_idx < self.layers && x < self.width && y < self.height {
             let layer_size = self.width * self.height;
-            self.squares
-                .get_mut(x + (y * self.width) + (layer_size * (layer_idx + 1)))
-        } else {
-            None
-        }
-    }
-
-    #[allow(dead_code)]
-    fn get_alpha_square(&self, x: usize, y: usize) -> Option<&Shape> {
-        if x < self.width && y < self.height {
-            self.squares.get(x + (y * self.width))
-        } else {
-            None
-        }
-    }
-
-    #[allow(dead_code)]
-    fn get_alpha_square_mut(&mut self, x: usize, y: usize) -> Option<&mut Shape> {
-        if x < self.width && y < self.height {
-            self.squares.get_mut(x + (y * self.width))
+            let idx = x
+                + (y * self.width)
+                + (layer_size * layer_idx)
+                + self.frame_offset(self.active_frame);
+            self.touch(idx);
+            self.pixels.get_mut(idx)
         } else {
             None
         }
     }
 
-    pub fn get_squares(&self) -> Vec<Shape> {
-        self.squares.clone()
-    }
-
-    pub fn get_squares_unhidden(&self) -> Vec<Shape> {
-        let layer_size = self.width * self.height;
-        let mut squares_unhidden: Vec<Shape> = self.squares.clone();
-        let mut start_idx = layer_size;
-        let mut end_idx = layer_size * 2;
-
-        for i in 0..self.layers {
-            if self.layers_to_show.get(i) == Some(&false) {
-                squares_unhidden.drain(start_idx..end_idx);
-                continue;
-            }
-
-            start_idx += layer_size;
-            end_idx += layer_size;
-        }
-
-        squares_unhidden
+    /// Keeps a copy of the chunk holding `idx` from before it is first written, so that
+    /// `take_change` can tell what changed.
+    fn touch(&mut self, idx: usize) {
+        let chunk = idx / HISTORY_CHUNK_SIZE;
+        let pixels = &self.pixels;
+        self.touched_chunks.entry(chunk).or_insert_with(|| {
+            let start = chunk * HISTORY_CHUNK_SIZE;
+            pixels[start..(start + HISTORY_CHUNK_SIZE).min(pixels.len())].to_vec()
+        });
     }
 
     /// Offers Performance Benefit :)
-    fn get_pixel_unchecked(&self, x: usize, y: usize, layer_idx: usize) -> &Color32 {
-        &self.pixels[x + (y * self.width) + (self.width * self.height * layer_idx)]
+    fn get_pixel_unchecked(
+        &self,
+        x: usize,
+        y: usize,
+        layer_idx: usize,
+        frame_idx: usize,
+    ) -> &Color32 {
+        &self.pixels[x
+            + (y * self.width)
+            + (self.width * self.height * layer_idx)
+            + self.frame_offset(frame_idx)]
     }
 
     pub fn change_stroke(&mut self, new_stroke: Stroke) {
@@ -247,20 +535,7 @@ impl Canvas {
         if x.is_negative() || y.is_negative() {
             return Err("Not on canvas".into());
         }
-        let (x, y) = (x as usize, y as usize);
-        if let Some(pixel) = self.get_pixel_mut(x, y, self.active_layer) {
-            *pixel = color;
-        } else {
-            return Err("Failed to get pixel".into());
-        }
-        if let Some(Shape::Rect(RectShape { fill, .. })) =
-            self.get_square_mut(x, y, self.active_layer)
-        {
-            *fill = color;
-            Ok(())
-        } else {
-            Err("Failed to get rectangle".into())
-        }
+        self.set_pixel_from_pixel_coords((x as usize, y as usize), color)
     }
 
     pub fn set_pixel_from_pixel_coords(
@@ -269,56 +544,251 @@ impl Canvas {
         color: Color32,
     ) -> Result<(), String> {
         let (x, y) = pixel_coords;
-
-        if let Some(pixel) = self.get_pixel_mut(x, y, self.active_layer) {
-            *pixel = color;
-        } else {
+        if x >= self.width || y >= self.height {
             return Err("Failed to get pixel".into());
         }
-        if let Some(Shape::Rect(RectShape { fill, .. })) =
-            self.get_square_mut(x, y, self.active_layer)
-        {
-            *fill = color;
-            Ok(())
-        } else {
-            Err("Failed to get rectangle".into())
+        self.paint_pixel(x as isize, y as isize, color);
+        Ok(())
+    }
+
+    /// Sets a pixel of the active layer, which is what every painting tool comes down
+    /// to. Pixels off the canvas or outside of the selection are left alone, returns
+    /// whether the pixel was painted.
+    pub fn paint_pixel(&mut self, x: isize, y: isize, color: Color32) -> bool {
+        if x.is_negative() || y.is_negative() {
+            return false;
+        }
+        let (x, y) = (x as usize, y as usize);
+        if !self.is_selected(x, y) {
+            return false;
+        }
+        match self.get_pixel_mut(x, y, self.active_layer) {
+            Some(pixel) => *pixel = color,
+            None => return false,
         }
+        self.refresh_image_pixel(x, y);
+        true
     }
 
-    pub fn set_pixels_from_brush(&mut self, screen_cords: &Pos2, radius: usize, color: Color32) {
-        let radius = if radius < 1 { 1 } else { radius as isize };
+    /// Puts the brush down at a screen position. Within a stroke the brush is dragged
+    /// along a line from where it was last put down, so fast strokes leave no gaps.
+    /// With `pixel_perfect` a one pixel brush leaves out the doubled up corner pixels
+    /// where the stroke turns, so it stays one pixel thin.
+    pub fn set_pixels_from_brush(
+        &mut self,
+        screen_cords: &Pos2,
+        radius: usize,
+        color: Color32,
+        pixel_perfect: bool,
+    ) {
+        let pos = self.camera.screen_cords_to_pixel_cords(screen_cords);
+        let mut stroke = self.brush_stroke.take().unwrap_or(BrushStroke {
+            last: pos,
+            path: Vec::new(),
+        });
+        let line = raster::line(stroke.last, pos);
+        if pixel_perfect && radius <= 1 {
+            for point in line {
+                self.extend_pixel_perfect(&mut stroke.path, point, color);
+            }
+        } else {
+            for (x, y) in self.painted_pixels(raster::stamp(&line, radius)) {
+                self.paint_pixel(x, y, color);
+            }
+        }
+        stroke.last = pos;
+        self.brush_stroke = Some(stroke);
+    }
 
-        let (center_w, center_h) = self.camera.screen_cords_to_pixel_cords(screen_cords);
-        for y in -radius..=radius {
-            for x in -radius..=radius {
-                if x * x + y * y < radius * radius {
-                    let (w, h) = (center_w + x, center_h + y);
-                    if w.is_negative() || h.is_negative() {
-                        continue;
+    /// Paints the next pixel of a pixel-perfect stroke, then takes back the pixel
+    /// before it if it turned out to be the corner of an L.
+    fn extend_pixel_perfect(
+        &mut self,
+        path: &mut Vec<StrokePixel>,
+        point: (isize, isize),
+        color: Color32,
+    ) {
+        if path.last().is_some_and(|(last, _)| *last == point) {
+            return;
+        }
+        let painted = self
+            .images(point)
+            .into_iter()
+            .map(|(x, y)| {
+                let before = (x >= 0 && y >= 0)
+                    .then(|| self.get_pixel(x as usize, y as usize, self.active_layer))
+                    .flatten()
+                    .copied();
+                self.paint_pixel(x, y, color);
+                ((x, y), before)
+            })
+            .collect();
+        path.push((point, painted));
+
+        // Every step is to a neighbour, so a pixel between two diagonal neighbours is
+        // always a corner the stroke can do without
+        if let [.., (a, _), _, (b, _)] = path[..] {
+            if (a.0 - b.0).abs() == 1 && (a.1 - b.1).abs() == 1 {
+                let (_, corner) = path.remove(path.len() - 2);
+                for ((x, y), before) in corner {
+                    if let Some(before) = before {
+                        self.paint_pixel(x, y, before);
                     }
-                    let _ = self.set_pixel_from_pixel_coords((w as usize, h as usize), color);
                 }
             }
         }
     }
 
-    pub fn set_pixels_from_rect_brush(
+    /// Ends the stroke being drawn, so the next one starts where it is put down.
+    pub fn end_stroke(&mut self) {
+        self.brush_stroke = None;
+    }
+
+    /// Draws the rectangle between two screen positions, with corners rounded off
+    /// pixel-perfectly by `style.corner_radius`. With `square` the rectangle is made
+    /// square.
+    pub fn set_pixels_from_rect(
+        &mut self,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        style: &ShapeStyle,
+        square: bool,
+    ) {
+        let bounds = self.shape_bounds(start_screen_cords, end_screen_cords, square);
+        for ((x, y), color) in self.rect_pixels(bounds, style) {
+            self.paint_pixel(x, y, color);
+        }
+    }
+
+    fn rect_pixels(
+        &self,
+        bounds: (isize, isize, isize, isize),
+        style: &ShapeStyle,
+    ) -> Vec<((isize, isize), Color32)> {
+        self.painted_colors(style.paint(
+            || raster::thick_rounded_rect(bounds, style.corner_radius, style.width),
+            || raster::filled_rounded_rect(bounds, style.corner_radius),
+        ))
+    }
+
+    /// Draws a line between two screen positions with a round brush of `radius`.
+    /// With `snap` the line snaps to the angles pixel art lines look best at.
+    pub fn set_pixels_from_line(
         &mut self,
         start_screen_cords: &Pos2,
         end_screen_cords: &Pos2,
+        radius: usize,
         color: Color32,
+        snap: bool,
     ) {
-        let (x1, y1) = self.camera.screen_cords_to_pixel_cords(start_screen_cords);
-        let (x2, y2) = self.camera.screen_cords_to_pixel_cords(end_screen_cords);
-        let (x1, y1, x2, y2) = (x1.min(x2), y1.min(y2), x1.max(x2), y1.max(y2));
-        for y in y1..=y2 {
-            for x in x1..=x2 {
-                if x.is_negative() || y.is_negative() {
-                    continue;
-                }
-                let _ = self.set_pixel_from_pixel_coords((x as usize, y as usize), color);
+        for (x, y) in self.line_pixels(start_screen_cords, end_screen_cords, radius, snap) {
+            self.paint_pixel(x, y, color);
+        }
+    }
+
+    fn line_pixels(
+        &self,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        radius: usize,
+        snap: bool,
+    ) -> Vec<(isize, isize)> {
+        let start = self.camera.screen_cords_to_pixel_cords(start_screen_cords);
+        let mut end = self.camera.screen_cords_to_pixel_cords(end_screen_cords);
+        if snap {
+            end = raster::snap_line(start, end);
+        }
+        self.painted_pixels(raster::stamp(&raster::line(start, end), radius))
+    }
+
+    /// Where painting a pixel ends up: the pixel and its mirror images under the
+    /// symmetry, wrapped back onto the canvas when tiling, each only once.
+    fn images(&self, pixel: (isize, isize)) -> Vec<(isize, isize)> {
+        let mut images = self.symmetry.images(pixel);
+        if self.tiling.wrap {
+            let (width, height) = (self.width as isize, self.height as isize);
+            for (x, y) in images.iter_mut() {
+                (*x, *y) = (x.rem_euclid(width), y.rem_euclid(height));
             }
+            images.sort_unstable();
+            images.dedup();
         }
+        images
+    }
+
+    /// Where painting each of `pixels` ends up, each pixel only once.
+    fn painted_pixels(&self, pixels: Vec<(isize, isize)>) -> Vec<(isize, isize)> {
+        if self.symmetry.mode == SymmetryMode::None && !self.tiling.wrap {
+            return pixels;
+        }
+        let mut painted: Vec<(isize, isize)> = pixels
+            .into_iter()
+            .flat_map(|pixel| self.images(pixel))
+            .collect();
+        painted.sort_unstable();
+        painted.dedup();
+        painted
+    }
+
+    /// Where painting each of the colored `pixels` ends up.
+    fn painted_colors(
+        &self,
+        pixels: Vec<((isize, isize), Color32)>,
+    ) -> Vec<((isize, isize), Color32)> {
+        pixels
+            .into_iter()
+            .flat_map(|(pixel, color)| {
+                let images = self.images(pixel);
+                images.into_iter().map(move |image| (image, color))
+            })
+            .collect()
+    }
+
+    /// Draws the ellipse that fits the rectangle between two screen positions, with an
+    /// outline `style.width` pixels thick. With `circle` the rectangle is made square.
+    pub fn set_pixels_from_ellipse(
+        &mut self,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        style: &ShapeStyle,
+        circle: bool,
+    ) {
+        let bounds = self.shape_bounds(start_screen_cords, end_screen_cords, circle);
+        for ((x, y), color) in self.ellipse_pixels(bounds, style) {
+            self.paint_pixel(x, y, color);
+        }
+    }
+
+    fn ellipse_pixels(
+        &self,
+        bounds: (isize, isize, isize, isize),
+        style: &ShapeStyle,
+    ) -> Vec<((isize, isize), Color32)> {
+        self.painted_colors(style.paint(
+            || raster::thick_ellipse(bounds, style.width),
+            || raster::filled_ellipse(bounds),
+        ))
+    }
+
+    /// Pixel rectangle `(x1, y1, x2, y2)` dragged out between two screen positions,
+    /// made square with `square`.
+    fn shape_bounds(
+        &self,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        square: bool,
+    ) -> (isize, isize, isize, isize) {
+        let start = self.camera.screen_cords_to_pixel_cords(start_screen_cords);
+        let mut end = self.camera.screen_cords_to_pixel_cords(end_screen_cords);
+        if square {
+            end = raster::square_end(start, end);
+        }
+        (
+            start.0.min(end.0),
+            start.1.min(end.1),
+            start.0.max(end.0),
+            start.1.max(end.1),
+        )
     }
 
     pub fn get_pixel_from_screen_cords(
@@ -333,124 +803,430 @@ impl Canvas {
         self.get_pixel(x as usize, y as usize, active_layer)
     }
 
-    pub fn get_screen_center(&self) -> &Pos2 {
-        &self.camera.screen_center
+    pub fn get_width(&self) -> usize {
+        self.width
     }
 
-    pub fn get_screen_center_mut(&mut self) -> &mut Pos2 {
-        &mut self.camera.screen_center
+    pub fn get_height(&self) -> usize {
+        self.height
     }
 
-    pub fn update_squares(&mut self) {
-        self.squares
-            .par_iter_mut()
-            .enumerate()
-            .for_each(|(idx, sq)| {
-                let l = (idx / (self.width * self.height)) as isize - 1; // -1 for the alpha layer.
-                let h = (idx % (self.width * self.height)) / self.width;
-                let w = (idx % (self.width * self.height)) % self.width;
-
-                if l < 0 {
-                    // Alpha layer
-                    let screen_cords = self
-                        .camera
-                        .pixel_cords_to_screen_cords(w as isize, h as isize);
-                    let color = if (h / self.alpha_ratio) % 2 == (w / self.alpha_ratio) % 2 {
-                        Color32::DARK_GRAY
-                    } else {
-                        Color32::LIGHT_GRAY
-                    };
-                    *sq = self.camera.square_from_screen_cords(screen_cords, color);
-                } else {
-                    // Rest of the layers
-                    let l = l as usize;
-
-                    let screen_cords = self
-                        .camera
-                        .pixel_cords_to_screen_cords(w as isize, h as isize);
-                    let color = self.pixels[w + (h * self.width) + (self.width * self.height * l)];
-                    *sq = self.camera.square_from_screen_cords(screen_cords, color);
-                }
-            });
+    pub fn get_export_options(&self) -> &ExportOptions {
+        &self.export_options
     }
 
-    pub fn get_circle_brush(&self, screen_cords: &Pos2, radius: usize) -> Vec<Shape> {
-        let radius = radius as isize;
-
-        let (w, h) = self.camera.screen_cords_to_pixel_cords(screen_cords);
-        let mut brush = Vec::new();
-        for y in -radius..=radius {
-            for x in -radius..=radius {
-                if x * x + y * y < radius * radius {
-                    brush.push(
-                        self.camera
-                            .square_from_pixel_cords(w + x, h + y, HIGHLIGHT_COLOR),
-                    )
-                }
-            }
-        }
-        brush
+    pub fn set_export_options(&mut self, options: ExportOptions) {
+        self.export_options = options;
     }
 
-    pub fn get_rect_brush(&self, start_screen_cords: &Pos2, end_screen_cords: &Pos2) -> Shape {
-        let (x1, y1) = self.camera.screen_cords_to_pixel_cords(start_screen_cords);
-        let (x2, y2) = self.camera.screen_cords_to_pixel_cords(end_screen_cords);
-        let (x1, y1, x2, y2) = (x1.min(x2), y1.min(y2), x1.max(x2), y1.max(y2));
-        Shape::from(RectShape::new(
-            Rect::from_min_max(
-                self.camera.pixel_cords_to_screen_cords(x1, y1),
-                self.camera.pixel_cords_to_screen_cords(x2 + 1, y2 + 1),
-            ),
-            Rounding::default(),
-            HIGHLIGHT_COLOR,
-            Stroke::NONE,
-        ))
+    pub fn get_screen_center(&self) -> &Pos2 {
+        &self.camera.screen_center
     }
 
-    pub fn zoom(&mut self, amount: &f32) {
-        self.camera.square_size = (self.camera.square_size * amount).clamp(2.0, 100.0).ceil();
-        self.update_squares();
+    pub fn get_screen_center_mut(&mut self) -> &mut Pos2 {
+        &mut self.camera.screen_center
     }
 
-    pub fn scroll(&mut self, amount: &Vec2) {
-        self.camera.pixel_center = Pos2 {
-            x: (self.camera.pixel_center.x
-                - amount.x * SCROLL_SENSITIVITY / self.camera.square_size)
-                .clamp(0.0, self.width as f32),
-            y: (self.camera.pixel_center.y
-                - amount.y * SCROLL_SENSITIVITY / self.camera.square_size)
-                .clamp(0.0, self.height as f32),
-        };
-        self.update_squares();
+    /// Recomposites the whole active frame, for changes that are not a few pixels.
+    pub fn update_image(&mut self) {
+        self.image = self.flatten(false);
+        self.dirty = Some((0, 0, self.width - 1, self.height - 1));
+        self.update_onion_image();
     }
 
-    pub fn get_stroke(&self) -> &Stroke {
-        &self.stroke
+    /// Recomposites a single pixel of the active frame.
+    fn refresh_image_pixel(&mut self, x: usize, y: usize) {
+        self.image[x + y * self.width] = self.composite_pixel(x, y, false);
+        self.mark_dirty(x, y);
     }
 
-    pub fn round_coords(coord: Pos2, interval: usize) -> Pos2 {
-        let interval_f32 = interval as f32;
-        let rounded_x = (coord.x / interval_f32).round() * interval_f32;
-        let rounded_y = (coord.y / interval_f32).round() * interval_f32;
-
-        Pos2::new(rounded_x, rounded_y)
+    fn mark_dirty(&mut self, x: usize, y: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((x1, y1, x2, y2)) => (x1.min(x), y1.min(y), x2.max(x), y2.max(y)),
+            None => (x, y, x, y),
+        });
     }
 
-    pub fn get_rgba_buffer(&self) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-        let mut rgba_buffer = ImageBuffer::new(self.width as u32, self.height as u32);
-        for i in 0..self.layers {
-            for y in 0..self.height {
-                for x in 0..self.width {
-                    let color32 = self.get_pixel_unchecked(x, y, i);
-                    let rgba = color32.to_srgba_unmultiplied();
-                    if i > 0 && rgba[3] == 0 {
-                        continue;
-                    }
-                    rgba_buffer.put_pixel(x as u32, y as u32, Rgba(rgba));
-                }
+    /// Uploads the parts of the image that changed since the canvas was last drawn.
+    pub fn update_textures(&mut self, ctx: &Context) {
+        let size = [self.width, self.height];
+        let Some(textures) = &mut self.textures else {
+            let checkerboard = ColorImage {
+                size: [2, 2],
+                pixels: vec![
+                    Color32::DARK_GRAY,
+                    Color32::LIGHT_GRAY,
+                    Color32::LIGHT_GRAY,
+                    Color32::DARK_GRAY,
+                ],
+            };
+            self.textures = Some(CanvasTextures {
+                image: ctx.load_texture(
+                    "Canvas",
+                    ColorImage {
+                        size,
+                        pixels: self.image.clone(),
+                    },
+                    TextureOptions::NEAREST,
+                ),
+                onion: ctx.load_texture(
+                    "Onion Skin",
+                    ColorImage {
+                        size,
+                        pixels: self.onion_image.clone(),
+                    },
+                    TextureOptions::NEAREST,
+                ),
+                checkerboard: ctx.load_texture(
+                    "Checkerboard",
+                    checkerboard,
+                    TextureOptions {
+                        wrap_mode: TextureWrapMode::Repeat,
+                        ..TextureOptions::NEAREST
+                    },
+                ),
+            });
+            self.dirty = None;
+            self.onion_dirty = false;
+            return;
+        };
+
+        if let Some((x1, y1, x2, y2)) = self.dirty.take() {
+            let region = ColorImage {
+                size: [x2 - x1 + 1, y2 - y1 + 1],
+                pixels: (y1..=y2)
+                    .flat_map(|y| &self.image[(x1 + y * self.width)..=(x2 + y * self.width)])
+                    .copied()
+                    .collect(),
+            };
+            textures
+                .image
+                .set_partial([x1, y1], region, TextureOptions::NEAREST);
+        }
+        if std::mem::take(&mut self.onion_dirty) {
+            textures.onion.set(
+                ColorImage {
+                    size,
+                    pixels: self.onion_image.clone(),
+                },
+                TextureOptions::NEAREST,
+            );
+        }
+    }
+
+    /// The checkerboard, onion skin and image, placed where the camera shows them.
+    /// When previewing tiling the checkerboard and image repeat on every side of the
+    /// canvas. Empty until `update_textures` has run once.
+    pub fn get_image_shapes(&self) -> Vec<Shape> {
+        let Some(textures) = &self.textures else {
+            return Vec::new();
+        };
+
+        let (width, height) = (self.width as isize, self.height as isize);
+        let full_uv = Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0));
+        // Each texel of the checkerboard covers `alpha_ratio` pixels, and it repeats
+        let checkerboard_uv = Rect::from_min_max(
+            Pos2::ZERO,
+            Pos2::new(
+                self.width as f32 / (2 * self.alpha_ratio) as f32,
+                self.height as f32 / (2 * self.alpha_ratio) as f32,
+            ),
+        );
+        // How many copies of the canvas there are on each side of it
+        let reach = if self.tiling.preview { 1 } else { 0 };
+        let tiles = (-reach..=reach).flat_map(|ty| (-reach..=reach).map(move |tx| (tx, ty)));
+
+        let mut shapes = Vec::new();
+        for (tx, ty) in tiles {
+            let rect = Rect::from_min_max(
+                self.camera
+                    .pixel_cords_to_screen_cords(tx * width, ty * height),
+                self.camera
+                    .pixel_cords_to_screen_cords((tx + 1) * width, (ty + 1) * height),
+            );
+            shapes.push(Shape::image(
+                textures.checkerboard.id(),
+                rect,
+                checkerboard_uv,
+                Color32::WHITE,
+            ));
+            // The frames around the active one only show on the canvas itself
+            if self.onion_skin.enabled && (tx, ty) == (0, 0) {
+                shapes.push(Shape::image(
+                    textures.onion.id(),
+                    rect,
+                    full_uv,
+                    Color32::WHITE,
+                ));
             }
+            shapes.push(Shape::image(
+                textures.image.id(),
+                rect,
+                full_uv,
+                Color32::WHITE,
+            ));
+        }
+        shapes
+    }
+
+    /// Composites the frames around the active one, furthest frames first and fainter
+    /// the further away they are. Nothing is done while the onion skin is off.
+    fn update_onion_image(&mut self) {
+        if !self.onion_skin.enabled {
+            return;
+        }
+
+        let previous_frames = (1..=self.onion_skin.previous).rev().filter_map(|d| {
+            self.active_frame
+                .checked_sub(d)
+                .map(|f| (f, d, ONION_PREVIOUS_TINT))
+        });
+        let next_frames = (1..=self.onion_skin.next)
+            .rev()
+            .map(|d| (self.active_frame + d, d, ONION_NEXT_TINT))
+            .filter(|(f, _, _)| *f < self.frames);
+
+        let mut onion_image = vec![Color32::TRANSPARENT; self.width * self.height];
+        for (frame_idx, distance, tint) in previous_frames.chain(next_frames) {
+            let opacity = self.onion_skin.opacity / distance as f32;
+            let frame = self.flatten_frame(frame_idx, false);
+            onion_image
+                .par_iter_mut()
+                .zip(frame)
+                .filter(|(_, color)| color.a() != 0)
+                .for_each(|(dst, color)| {
+                    let [r, g, b, a] = color.to_srgba_unmultiplied();
+                    let onion_color = Color32::from_rgba_unmultiplied(
+                        ((r as u16 + tint.r() as u16) / 2) as u8,
+                        ((g as u16 + tint.g() as u16) / 2) as u8,
+                        ((b as u16 + tint.b() as u16) / 2) as u8,
+                        (a as f32 * opacity).round() as u8,
+                    );
+                    *dst = blend_over(*dst, onion_color);
+                });
+        }
+        self.onion_image = onion_image;
+        self.onion_dirty = true;
+    }
+
+    pub fn get_symmetry(&self) -> &Symmetry {
+        &self.symmetry
+    }
+
+    pub fn set_symmetry(&mut self, symmetry: Symmetry) {
+        self.symmetry = symmetry;
+    }
+
+    pub fn get_tiling(&self) -> &Tiling {
+        &self.tiling
+    }
+
+    pub fn set_tiling(&mut self, tiling: Tiling) {
+        self.tiling = tiling;
+    }
+
+    pub fn get_onion_skin(&self) -> &OnionSkin {
+        &self.onion_skin
+    }
+
+    pub fn set_onion_skin(&mut self, onion_skin: OnionSkin) {
+        if self.onion_skin != onion_skin {
+            self.onion_skin = onion_skin;
+            self.update_onion_image();
         }
-        rgba_buffer
+    }
+
+    pub fn get_circle_brush(&self, screen_cords: &Pos2, radius: usize) -> Vec<Shape> {
+        let pos = self.camera.screen_cords_to_pixel_cords(screen_cords);
+        self.painted_pixels(raster::stamp(&[pos], radius))
+            .into_iter()
+            .map(|(x, y)| self.camera.square_from_pixel_cords(x, y, HIGHLIGHT_COLOR))
+            .collect()
+    }
+
+    pub fn get_line_brush(
+        &self,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        radius: usize,
+        snap: bool,
+    ) -> Vec<Shape> {
+        let pixels = self.line_pixels(start_screen_cords, end_screen_cords, radius, snap);
+        self.highlight_columns(&raster::columns(pixels))
+    }
+
+    pub fn get_ellipse_brush(
+        &mut self,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        style: &ShapeStyle,
+        circle: bool,
+    ) -> Vec<Shape> {
+        let bounds = self.shape_bounds(start_screen_cords, end_screen_cords, circle);
+        self.shape_preview(ShapeKind::Ellipse, bounds, style)
+    }
+
+    pub fn get_rect_brush(
+        &mut self,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        style: &ShapeStyle,
+        square: bool,
+    ) -> Vec<Shape> {
+        let bounds = self.shape_bounds(start_screen_cords, end_screen_cords, square);
+        // Filled rectangles with square corners cover every pixel of their bounds
+        let whole = style.mode != ShapeMode::Outline && style.corner_radius == 0;
+        if whole && self.symmetry.mode == SymmetryMode::None && !self.tiling.wrap {
+            return vec![Shape::from(RectShape::new(
+                self.bounds_to_screen(bounds),
+                Rounding::default(),
+                HIGHLIGHT_COLOR,
+                Stroke::NONE,
+            ))];
+        }
+        self.shape_preview(ShapeKind::Rect, bounds, style)
+    }
+
+    /// Highlights a shape being dragged out. Its pixels only get worked out again when
+    /// the shape changes, not every time it is drawn.
+    fn shape_preview(
+        &mut self,
+        kind: ShapeKind,
+        bounds: (isize, isize, isize, isize),
+        style: &ShapeStyle,
+    ) -> Vec<Shape> {
+        let key = ShapePreviewKey {
+            kind,
+            bounds,
+            style: *style,
+            symmetry: self.symmetry,
+            tiling: self.tiling,
+        };
+        if self.shape_preview.as_ref().map(|p| p.key) != Some(key) {
+            let pixels = match kind {
+                ShapeKind::Rect => self.rect_pixels(bounds, style),
+                ShapeKind::Ellipse => self.ellipse_pixels(bounds, style),
+            };
+            let columns = raster::columns(pixels.into_iter().map(|(pixel, _)| pixel).collect());
+            self.shape_preview = Some(ShapePreview { key, columns });
+        }
+        self.highlight_columns(&self.shape_preview.as_ref().unwrap().columns)
+    }
+
+    /// Highlights columns `(x, y1, y2)` of pixels, each as one rectangle so large
+    /// filled shapes don't become thousands of shapes.
+    fn highlight_columns(&self, columns: &[(isize, isize, isize)]) -> Vec<Shape> {
+        columns
+            .iter()
+            .map(|&(x, y1, y2)| {
+                Shape::from(RectShape::new(
+                    Rect::from_min_max(
+                        self.camera.pixel_cords_to_screen_cords(x, y1),
+                        self.camera.pixel_cords_to_screen_cords(x + 1, y2 + 1),
+                    ),
+                    Rounding::default(),
+                    HIGHLIGHT_COLOR,
+                    Stroke::NONE,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn zoom(&mut self, amount: &f32) {
+        let size = (self.camera.square_size * amount).clamp(MIN_SQUARE_SIZE, MAX_SQUARE_SIZE);
+        // Whole screen pixels per canvas pixel keep the grid even. Rounding away from
+        // the current size makes sure small steps still zoom. Large canvases need less
+        // than a screen pixel per canvas pixel to fit on screen.
+        self.camera.square_size = if size < 1.0 {
+            size
+        } else if *amount > 1.0 {
+            size.ceil()
+        } else {
+            size.floor()
+        };
+    }
+
+    pub fn scroll(&mut self, amount: &Vec2) {
+        self.camera.pixel_center = Pos2 {
+            x: (self.camera.pixel_center.x
+                - amount.x * SCROLL_SENSITIVITY / self.camera.square_size)
+                .clamp(0.0, self.width as f32),
+            y: (self.camera.pixel_center.y
+                - amount.y * SCROLL_SENSITIVITY / self.camera.square_size)
+                .clamp(0.0, self.height as f32),
+        };
+    }
+
+    pub fn get_stroke(&self) -> &Stroke {
+        &self.stroke
+    }
+
+    pub fn round_coords(coord: Pos2, interval: usize) -> Pos2 {
+        let interval_f32 = interval as f32;
+        let rounded_x = (coord.x / interval_f32).round() * interval_f32;
+        let rounded_y = (coord.y / interval_f32).round() * interval_f32;
+
+        Pos2::new(rounded_x, rounded_y)
+    }
+
+    /// Composites the layers at a single pixel of the active frame, bottom layer
+    /// first, skipping hidden layers unless `include_hidden` is set.
+    pub fn composite_pixel(&self, x: usize, y: usize, include_hidden: bool) -> Color32 {
+        self.composite_frame_pixel(self.active_frame, x, y, include_hidden)
+    }
+
+    fn composite_frame_pixel(
+        &self,
+        frame_idx: usize,
+        x: usize,
+        y: usize,
+        include_hidden: bool,
+    ) -> Color32 {
+        (0..self.layers)
+            .filter(|&i| include_hidden || self.is_layer_shown(i))
+            .fold(Color32::TRANSPARENT, |dst, i| {
+                blend_over(dst, *self.get_pixel_unchecked(x, y, i, frame_idx))
+            })
+    }
+
+    /// Composites all layers of the active frame into a single `width * height` image.
+    pub fn flatten(&self, include_hidden: bool) -> Vec<Color32> {
+        self.flatten_frame(self.active_frame, include_hidden)
+    }
+
+    /// Composites all layers of a frame into a single `width * height` image.
+    pub fn flatten_frame(&self, frame_idx: usize, include_hidden: bool) -> Vec<Color32> {
+        (0..self.width * self.height)
+            .into_par_iter()
+            .map(|idx| {
+                self.composite_frame_pixel(
+                    frame_idx,
+                    idx % self.width,
+                    idx / self.width,
+                    include_hidden,
+                )
+            })
+            .collect()
+    }
+
+    pub fn get_rgba_buffer(&self) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let flattened = self.flatten(false);
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            Rgba(flattened[x as usize + y as usize * self.width].to_srgba_unmultiplied())
+        })
+    }
+
+    /// Creates a single layer canvas holding the pixels of the buffer.
+    pub fn from_rgba_buffer(rgba_buffer: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Self {
+        let mut canvas = Self::new(rgba_buffer.width() as usize, rgba_buffer.height() as usize);
+        for (x, y, rgba) in rgba_buffer.enumerate_pixels() {
+            let Rgba([r, g, b, a]) = *rgba;
+            *canvas.get_pixel_mut(x as usize, y as usize, 0).unwrap() =
+                Color32::from_rgba_unmultiplied(r, g, b, a);
+        }
+        canvas.forget_edits();
+        canvas.update_image();
+        canvas
     }
 
     pub fn get_num_layers(&self) -> usize {
@@ -458,173 +1234,1400 @@ impl Canvas {
     }
 
     pub fn add_layer(&mut self) {
-        self.layers += 1;
         self.layer_names.push(self.layer_name_cnt.to_string());
         self.layer_name_cnt += 1;
         self.layers_to_show.push(true);
 
-        // Reallocate pixels vec and shapes vec
+        // Add an empty cel to the end of every frame
         let layer_size = self.width * self.height;
-        self.pixels
-            .resize(self.pixels.len() + layer_size, Color32::TRANSPARENT);
-        self.squares.resize(
-            self.squares.len() + layer_size,
-            Shape::from(RectShape::new(
-                Rect::from_min_size(Pos2::default(), Vec2::default()),
-                Rounding::default(),
+        self.record_edit(Edit::InsertLayer {
+            layer_idx: self.layers,
+            cels: CompressedPixels::encode(std::iter::repeat_n(
                 Color32::TRANSPARENT,
-                Stroke::NONE,
+                layer_size * self.frames,
             )),
-        );
-        self.update_squares();
+        });
+        self.update_image();
     }
 
     pub fn get_active_layer(&self) -> usize {
         self.active_layer
     }
 
+    /// Switching layers is not recorded in the history, so the next change does not
+    /// pick it up either.
     pub fn set_active_layer(&mut self, layer_idx: usize) {
         self.active_layer = layer_idx;
+        self.recorded_meta.active_layer = layer_idx;
     }
 
     pub fn delete_layer(&mut self, layer_idx: usize) {
-        let layer_size = self.width * self.height;
-
-        let start_idx = layer_size * layer_idx;
-        let end_idx = layer_size * (layer_idx + 1);
-
-        // check range, then delete layer from pixels and resize
-        if start_idx < self.pixels.len() && end_idx <= self.pixels.len() && start_idx < end_idx {
-            self.pixels.drain(start_idx..end_idx);
-        } else {
-            panic!(
-                "Range {}-{} is out of bounds for pixels",
-                start_idx, end_idx
-            );
-        }
-
-        // for pixels, need to update start and end
-        let start_idx = start_idx + layer_size;
-        let end_idx = end_idx + layer_size;
-
-        // delete from squares in the same way
-        if start_idx < self.squares.len() && end_idx <= self.squares.len() && start_idx < end_idx {
-            self.squares.drain(start_idx..end_idx);
-        } else {
+        // check range, then delete the layer's cel from every frame
+        if layer_idx >= self.layers {
             panic!(
-                "Range {}-{} is out of bounds for squares",
-                start_idx, end_idx
+                "Layer {} is out of bounds for {} layers",
+                layer_idx, self.layers
             );
         }
+        let cels = (0..self.frames).flat_map(|f| self.get_cel(layer_idx, f).iter().copied());
+        let cels = CompressedPixels::encode(cels);
+        self.record_edit(Edit::RemoveLayer { layer_idx, cels });
 
         // update other variables
-        if self.layers > 1 && self.active_layer == self.layers - 1 {
+        if self.active_layer >= self.layers && self.active_layer > 0 {
             self.active_layer -= 1
         }
 
-        self.layers -= 1;
         self.layer_names.remove(layer_idx);
         self.layers_to_show.remove(layer_idx);
 
-        self.update_squares();
+        self.update_image();
     }
 
     pub fn get_layer_name(&self, layer_idx: usize) -> &String {
         self.layer_names.get(layer_idx).unwrap()
     }
 
+    pub fn is_layer_shown(&self, layer_idx: usize) -> bool {
+        self.layers_to_show.get(layer_idx) != Some(&false)
+    }
+
     pub fn get_layers_to_show_mut(&mut self) -> &mut Vec<bool> {
         &mut self.layers_to_show
     }
 
+    pub fn get_num_frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn get_active_frame(&self) -> usize {
+        self.active_frame
+    }
+
+    /// Like switching layers, switching frames is not recorded in the history.
+    pub fn set_active_frame(&mut self, frame_idx: usize) {
+        if frame_idx < self.frames && frame_idx != self.active_frame {
+            self.active_frame = frame_idx;
+            self.recorded_meta.active_frame = frame_idx;
+            self.update_image();
+        }
+    }
+
+    pub fn get_frame_duration(&self, frame_idx: usize) -> u32 {
+        self.frame_durations[frame_idx]
+    }
+
+    pub fn get_frame_duration_mut(&mut self, frame_idx: usize) -> &mut u32 {
+        &mut self.frame_durations[frame_idx]
+    }
+
+    /// Inserts an empty frame after the active one and makes it active.
+    pub fn add_frame(&mut self) {
+        let frame_size = self.frame_offset(1);
+        self.record_edit(Edit::InsertFrame {
+            frame_idx: self.active_frame + 1,
+            cels: CompressedPixels::encode(std::iter::repeat_n(Color32::TRANSPARENT, frame_size)),
+        });
+        self.frame_durations
+            .insert(self.active_frame + 1, DEFAULT_FRAME_DURATION);
+        self.active_frame += 1;
+
+        self.update_image();
+    }
+
+    /// Inserts a copy of the active frame after it and makes the copy active.
+    pub fn duplicate_frame(&mut self) {
+        let start_idx = self.frame_offset(self.active_frame);
+        let end_idx = self.frame_offset(self.active_frame + 1);
+        self.record_edit(Edit::InsertFrame {
+            frame_idx: self.active_frame + 1,
+            cels: CompressedPixels::encode(self.pixels[start_idx..end_idx].iter().copied()),
+        });
+        self.frame_durations.insert(
+            self.active_frame + 1,
+            self.frame_durations[self.active_frame],
+        );
+        self.active_frame += 1;
+
+        self.update_image();
+    }
+
+    /// Deletes a frame, unless it is the only one left.
+    pub fn delete_frame(&mut self, frame_idx: usize) {
+        if self.frames <= 1 || frame_idx >= self.frames {
+            return;
+        }
+
+        let start_idx = self.frame_offset(frame_idx);
+        let end_idx = self.frame_offset(frame_idx + 1);
+        self.record_edit(Edit::RemoveFrame {
+            frame_idx,
+            cels: CompressedPixels::encode(self.pixels[start_idx..end_idx].iter().copied()),
+        });
+        self.frame_durations.remove(frame_idx);
+        if self.active_frame > frame_idx || self.active_frame == self.frames {
+            self.active_frame -= 1;
+        }
+
+        self.update_image();
+    }
+
+    /// Moves a frame to a new position in the timeline, keeping it active if it was.
+    pub fn move_frame(&mut self, from_idx: usize, to_idx: usize) {
+        if from_idx >= self.frames || to_idx >= self.frames || from_idx == to_idx {
+            return;
+        }
+
+        self.record_edit(Edit::MoveFrame { from_idx, to_idx });
+        let duration = self.frame_durations.remove(from_idx);
+        self.frame_durations.insert(to_idx, duration);
+
+        if self.active_frame == from_idx {
+            self.active_frame = to_idx;
+        } else if from_idx < self.active_frame && self.active_frame <= to_idx {
+            self.active_frame -= 1;
+        } else if to_idx <= self.active_frame && self.active_frame < from_idx {
+            self.active_frame += 1;
+        }
+
+        self.update_image();
+    }
+
+    /// Inserts a cel for every frame at `layer_idx`, `cels` holding them frame by frame.
+    fn insert_layer_cels(&mut self, layer_idx: usize, cels: &CompressedPixels) {
+        let layer_size = self.width * self.height;
+        let cels = cels.decode();
+        let mut pixels = Vec::with_capacity(self.pixels.len() + cels.len());
+        for (frame, cel) in self
+            .pixels
+            .chunks(layer_size * self.layers)
+            .zip(cels.chunks(layer_size))
+        {
+            pixels.extend_from_slice(&frame[..(layer_size * layer_idx)]);
+            pixels.extend_from_slice(cel);
+            pixels.extend_from_slice(&frame[(layer_size * layer_idx)..]);
+        }
+        self.pixels = pixels;
+        self.layers += 1;
+    }
+
+    fn remove_layer_cels(&mut self, layer_idx: usize) {
+        let layer_size = self.width * self.height;
+        let layers = self.layers;
+        self.pixels = self
+            .pixels
+            .chunks(layer_size)
+            .enumerate()
+            .filter(|(i, _)| i % layers != layer_idx)
+            .flat_map(|(_, cel)| cel.iter().copied())
+            .collect();
+        self.layers -= 1;
+    }
+
+    fn insert_frame_cels(&mut self, frame_idx: usize, cels: &CompressedPixels) {
+        let insert_idx = self.frame_offset(frame_idx);
+        self.pixels.splice(insert_idx..insert_idx, cels.iter());
+        self.frames += 1;
+    }
+
+    fn remove_frame_cels(&mut self, frame_idx: usize) {
+        let start_idx = self.frame_offset(frame_idx);
+        let end_idx = self.frame_offset(frame_idx + 1);
+        self.pixels.drain(start_idx..end_idx);
+        self.frames -= 1;
+    }
+
+    fn move_frame_cels(&mut self, from_idx: usize, to_idx: usize) {
+        let frame_size = self.frame_offset(1);
+        let start_idx = self.frame_offset(from_idx.min(to_idx));
+        let end_idx = self.frame_offset(from_idx.max(to_idx) + 1);
+        if from_idx < to_idx {
+            self.pixels[start_idx..end_idx].rotate_left(frame_size);
+        } else {
+            self.pixels[start_idx..end_idx].rotate_right(frame_size);
+        }
+    }
+
+    /// Applies an edit to the pixels, or reverts it when `forward` is false. Layer and
+    /// frame counts follow along, the rest of the metadata is up to the caller.
+    fn apply_edit(&mut self, edit: &Edit, forward: bool) {
+        match edit {
+            Edit::Pixels(spans) => {
+                for span in spans {
+                    let colors = if forward { &span.after } else { &span.before };
+                    for (pixel, color) in self.pixels[span.start..].iter_mut().zip(colors.iter()) {
+                        *pixel = color;
+                    }
+                }
+            }
+            Edit::InsertLayer { layer_idx, cels } if forward => {
+                self.insert_layer_cels(*layer_idx, cels)
+            }
+            Edit::RemoveLayer { layer_idx, cels } if !forward => {
+                self.insert_layer_cels(*layer_idx, cels)
+            }
+            Edit::InsertLayer { layer_idx, .. } | Edit::RemoveLayer { layer_idx, .. } => {
+                self.remove_layer_cels(*layer_idx)
+            }
+            Edit::InsertFrame { frame_idx, cels } if forward => {
+                self.insert_frame_cels(*frame_idx, cels)
+            }
+            Edit::RemoveFrame { frame_idx, cels } if !forward => {
+                self.insert_frame_cels(*frame_idx, cels)
+            }
+            Edit::InsertFrame { frame_idx, .. } | Edit::RemoveFrame { frame_idx, .. } => {
+                self.remove_frame_cels(*frame_idx)
+            }
+            Edit::MoveFrame { from_idx, to_idx } if forward => {
+                self.move_frame_cels(*from_idx, *to_idx)
+            }
+            Edit::MoveFrame { from_idx, to_idx } => self.move_frame_cels(*to_idx, *from_idx),
+            Edit::Selection { before, after } => {
+                let selection = if forward { after } else { before };
+                self.selection = selection.as_ref().map(CompressedSelection::decode);
+            }
+        }
+    }
+
+    /// Applies a layer or frame edit and keeps it for the next change.
+    fn record_edit(&mut self, edit: Edit) {
+        // Pixel indices shift with layers and frames, so pixel edits are settled first
+        self.flush_pixel_edits();
+        self.apply_edit(&edit, true);
+        self.edits.push(edit);
+    }
+
+    /// Turns the chunks written since the last flush into a pixel edit holding only
+    /// the pixels that actually changed.
+    fn flush_pixel_edits(&mut self) {
+        let mut chunks: Vec<(usize, Vec<Color32>)> = self.touched_chunks.drain().collect();
+        chunks.sort_unstable_by_key(|(chunk, _)| *chunk);
+
+        let mut spans = Vec::new();
+        for (chunk, before) in chunks {
+            let offset = chunk * HISTORY_CHUNK_SIZE;
+            let after = &self.pixels[offset..(offset + before.len())];
+            let mut changed = (0..before.len())
+                .filter(|&i| before[i] != after[i])
+                .peekable();
+            while let Some(start) = changed.next() {
+                let mut end = start + 1;
+                while let Some(&next) = changed.peek() {
+                    if next - end > HISTORY_SPAN_GAP {
+                        break;
+                    }
+                    end = next + 1;
+                    changed.next();
+                }
+                spans.push(PixelSpan {
+                    start: offset + start,
+                    before: CompressedPixels::encode(before[start..end].iter().copied()),
+                    after: CompressedPixels::encode(after[start..end].iter().copied()),
+                });
+            }
+        }
+
+        if !spans.is_empty() {
+            self.edits.push(Edit::Pixels(spans));
+        }
+    }
+
+    /// Whether anything was drawn or edited since the last change was taken.
+    pub fn has_pending_edits(&self) -> bool {
+        !self.edits.is_empty() || !self.touched_chunks.is_empty()
+    }
+
+    /// Everything that changed since the last time this was called, or `None` if
+    /// nothing did.
+    pub fn take_change(&mut self, name: String) -> Option<Change> {
+        // A move that is still going on would restore pixels from before the change
+        self.floating = None;
+        self.flush_pixel_edits();
+        if self.selection != self.recorded_selection {
+            let before = std::mem::replace(&mut self.recorded_selection, self.selection.clone());
+            self.edits.push(Edit::Selection {
+                before: before.as_ref().map(CompressedSelection::encode),
+                after: self.selection.as_ref().map(CompressedSelection::encode),
+            });
+        }
+        let after = self.meta();
+        if self.edits.is_empty() && after == self.recorded_meta {
+            return None;
+        }
+
+        Some(Change {
+            name,
+            before: std::mem::replace(&mut self.recorded_meta, after.clone()),
+            after,
+            edits: std::mem::take(&mut self.edits),
+        })
+    }
+
+    /// Redoes a change taken from this canvas.
+    pub fn apply_change(&mut self, change: &Change) {
+        for edit in &change.edits {
+            self.apply_edit(edit, true);
+        }
+        self.finish_change(change, &change.after);
+    }
+
+    /// Undoes a change taken from this canvas.
+    pub fn revert_change(&mut self, change: &Change) {
+        for edit in change.edits.iter().rev() {
+            self.apply_edit(edit, false);
+        }
+        self.finish_change(change, &change.before);
+    }
+
+    fn finish_change(&mut self, change: &Change, meta: &CanvasMeta) {
+        // The selection is drawn on top of the image, so it needs no recompositing
+        let only_pixels = change
+            .edits
+            .iter()
+            .all(|e| matches!(e, Edit::Pixels(_) | Edit::Selection { .. }));
+        let frame_size = self.frame_offset(1);
+        let mut touched_frames: Vec<usize> = change
+            .edits
+            .iter()
+            .filter_map(|e| match e {
+                Edit::Pixels(spans) => Some(spans),
+                _ => None,
+            })
+            .flatten()
+            .flat_map(|span| {
+                (span.start / frame_size)..=((span.start + span.before.len() - 1) / frame_size)
+            })
+            .collect();
+        touched_frames.sort_unstable();
+        touched_frames.dedup();
+
+        // Switching layers and frames isn't recorded, so the ones in a change can have
+        // been left since. Changes to just the pixels stay on the layer and frame being
+        // shown, unless they are all on other frames, which are then shown instead.
+        let meta = &CanvasMeta {
+            active_layer: if only_pixels {
+                self.active_layer
+            } else {
+                meta.active_layer
+            },
+            active_frame: match touched_frames.first() {
+                _ if !only_pixels => meta.active_frame,
+                Some(&frame_idx) if !touched_frames.contains(&self.active_frame) => frame_idx,
+                _ => self.active_frame,
+            },
+            ..meta.clone()
+        };
+        let same_view =
+            meta.active_frame == self.active_frame && meta.layers_to_show == self.layers_to_show;
+        self.load_meta(meta);
+        self.forget_edits();
+
+        if !(only_pixels && same_view) {
+            self.update_image();
+            return;
+        }
+
+        // Only recomposite the pixels the change touched
+        let layer_size = self.width * self.height;
+        if touched_frames.iter().any(|&f| f != self.active_frame) {
+            self.update_onion_image();
+        }
+        for edit in &change.edits {
+            let Edit::Pixels(spans) = edit else {
+                continue;
+            };
+            for span in spans {
+                for idx in span.start..(span.start + span.before.len()) {
+                    if idx / frame_size == self.active_frame {
+                        let idx = idx % layer_size;
+                        self.refresh_image_pixel(idx % self.width, idx / self.width);
+                    }
+                }
+            }
+        }
+    }
+
+    fn meta(&self) -> CanvasMeta {
+        CanvasMeta {
+            layers: self.layers,
+            active_layer: self.active_layer,
+            layer_names: self.layer_names.clone(),
+            layer_name_cnt: self.layer_name_cnt,
+            layers_to_show: self.layers_to_show.clone(),
+            frames: self.frames,
+            active_frame: self.active_frame,
+            frame_durations: self.frame_durations.clone(),
+        }
+    }
+
+    fn load_meta(&mut self, meta: &CanvasMeta) {
+        self.layers = meta.layers;
+        self.active_layer = meta.active_layer;
+        self.layer_names = meta.layer_names.clone();
+        self.layer_name_cnt = meta.layer_name_cnt;
+        self.layers_to_show = meta.layers_to_show.clone();
+        self.frames = meta.frames;
+        self.active_frame = meta.active_frame;
+        self.frame_durations = meta.frame_durations.clone();
+    }
+
+    /// Treats the canvas as it is now as unchanged, for canvases that were just built.
+    fn forget_edits(&mut self) {
+        self.touched_chunks.clear();
+        self.edits.clear();
+        self.recorded_meta = self.meta();
+        self.recorded_selection = self.selection.clone();
+    }
+
+    /// Fills the area under the cursor on the active layer with `fill_color`, as
+    /// picked out by `fill_mask`.
     pub fn fill(
         &mut self,
         screen_coord: &Pos2,
-        target_color: &Color32,
         fill_color: &Color32,
+        options: &FillOptions,
     ) -> Result<(), String> {
-        // Check if trying to fill a color with itself
-        if target_color == fill_color {
-            return Err(String::from("Tried to fill with the same color"));
-        }
-
-        let pixel_coord = self.camera.screen_cords_to_pixel_cords(screen_coord);
-        if self
-            .get_pixel(
-                pixel_coord.0 as usize,
-                pixel_coord.1 as usize,
-                self.active_layer,
-            )
-            .is_none()
+        let (x, y) = self.camera.screen_cords_to_pixel_cords(screen_coord);
+        // Wrapping brings clicks on the copies around the canvas back onto it
+        if !self.tiling.wrap
+            && (x.is_negative()
+                || y.is_negative()
+                || x as usize >= self.width
+                || y as usize >= self.height)
         {
             return Err(String::from("Tried to fill outside of canvas"));
         }
 
-        self.flood_fill(pixel_coord, target_color, fill_color);
+        // Each mirror image of the clicked pixel fills its own area, all of them found
+        // before any is filled
+        let mut mask = vec![false; self.width * self.height];
+        for (x, y) in self.images((x, y)) {
+            if x.is_negative()
+                || y.is_negative()
+                || x as usize >= self.width
+                || y as usize >= self.height
+            {
+                continue;
+            }
+            let image_mask = self.fill_mask((x as usize, y as usize), options);
+            for (m, image_m) in mask.iter_mut().zip(image_mask) {
+                *m |= image_m;
+            }
+        }
+        for (idx, _) in mask.iter().enumerate().filter(|(_, &m)| m) {
+            let (x, y) = (idx % self.width, idx / self.width);
+            if self.get_pixel(x, y, self.active_layer) != Some(fill_color) {
+                self.paint_pixel(x as isize, y as isize, *fill_color);
+            }
+        }
         Ok(())
     }
 
-    fn flood_fill(
+    /// Marks the pixels of the active frame that match the color at `start`, as a
+    /// `width * height` mask. Contiguous fills walk the canvas a row span at a time
+    /// with a stack instead of recursing, so large areas can't overflow the stack.
+    pub fn fill_mask(&self, start: (usize, usize), options: &FillOptions) -> Vec<bool> {
+        let (width, height) = (self.width, self.height);
+        let sample: &[Color32] = if options.sample_merged {
+            &self.image
+        } else {
+            self.get_cel(self.active_layer, self.active_frame)
+        };
+        let target = sample[start.0 + start.1 * width];
+        let matches = |idx: usize| options.matches(target, sample[idx]);
+
+        let mut mask = vec![false; width * height];
+        if !options.contiguous {
+            for (idx, m) in mask.iter_mut().enumerate() {
+                *m = matches(idx);
+            }
+            return mask;
+        }
+
+        let mut stack = vec![start];
+        while let Some((x, y)) = stack.pop() {
+            let row = y * width;
+            if mask[x + row] || !matches(x + row) {
+                continue;
+            }
+
+            // Grow the span as far as it goes both ways
+            let mut left = x;
+            while left > 0 && !mask[left - 1 + row] && matches(left - 1 + row) {
+                left -= 1;
+            }
+            let mut right = x;
+            while right + 1 < width && !mask[right + 1 + row] && matches(right + 1 + row) {
+                right += 1;
+            }
+            mask[row + left..=row + right].fill(true);
+
+            // Seed each run of matching pixels touching the span in the rows above
+            // and below, diagonal neighbours included for 8-way fills
+            let (from, to) = match options.diagonal {
+                true => (left.saturating_sub(1), (right + 1).min(width - 1)),
+                false => (left, right),
+            };
+            for ny in [y.wrapping_sub(1), y + 1] {
+                if ny >= height {
+                    continue;
+                }
+                let mut in_run = false;
+                for nx in from..=to {
+                    let idx = nx + ny * width;
+                    if !mask[idx] && matches(idx) {
+                        if !in_run {
+                            stack.push((nx, ny));
+                        }
+                        in_run = true;
+                    } else {
+                        in_run = false;
+                    }
+                }
+            }
+        }
+        mask
+    }
+
+    pub fn get_selection(&self) -> Option<&Selection> {
+        self.selection.as_ref()
+    }
+
+    /// Replaces the selection, an empty one selecting nothing is the same as `None`.
+    pub fn set_selection(&mut self, selection: Option<Selection>) {
+        self.selection = selection.filter(|s| !s.is_empty());
+    }
+
+    /// Whether tools may change the pixel, which they can anywhere without a selection.
+    pub fn is_selected(&self, x: usize, y: usize) -> bool {
+        match &self.selection {
+            Some(selection) => selection.contains(x, y),
+            None => true,
+        }
+    }
+
+    /// Selects the rectangle between two screen positions, clipped to the canvas. A
+    /// single click without a drag clears the selection when replacing it.
+    pub fn select_rect(
         &mut self,
-        pixel_coord: (isize, isize),
-        target_color: &Color32,
-        fill_color: &Color32,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        mode: SelectionMode,
     ) {
-        let (x, y) = pixel_coord;
+        let start = self.camera.screen_cords_to_pixel_cords(start_screen_cords);
+        let end = self.camera.screen_cords_to_pixel_cords(end_screen_cords);
+        if start == end {
+            if mode == SelectionMode::Replace {
+                self.selection = None;
+            }
+            return;
+        }
 
-        // Checking for out of bounds, and then casting as usize for compatibility with other functions
-        if x.is_negative() || y.is_negative() {
+        let (w, h) = (self.width as isize, self.height as isize);
+        let rect = if start.0.max(end.0) < 0
+            || start.1.max(end.1) < 0
+            || start.0.min(end.0) >= w
+            || start.1.min(end.1) >= h
+        {
+            Selection::from_mask(
+                self.width,
+                self.height,
+                vec![false; self.width * self.height],
+            )
+        } else {
+            let clamp =
+                |(x, y): (isize, isize)| (x.clamp(0, w - 1) as usize, y.clamp(0, h - 1) as usize);
+            Selection::rect(self.width, self.height, clamp(start), clamp(end))
+        };
+        self.combine_selection(rect, mode);
+    }
+
+    /// Selects the pixels inside a polygon, given in the fractional pixel coordinates
+    /// from `screen_to_pixel_pos`.
+    pub fn select_polygon(&mut self, points: &[Pos2], mode: SelectionMode) {
+        if points.len() < 3 {
             return;
         }
-        let x_usize = x as usize;
-        let y_usize = y as usize;
-
-        // check for target color, set if it is correct color
-        match self.get_pixel_mut(x_usize, y_usize, self.active_layer) {
-            Some(color) => {
-                if color == target_color {
-                    // Setting the color here
-                    if self
-                        .set_pixel_from_pixel_coords((x_usize, y_usize), *fill_color)
-                        .is_err()
-                    {
-                        return;
+        let polygon = Selection::polygon(self.width, self.height, points);
+        self.combine_selection(polygon, mode);
+    }
+
+    /// Magic wand, selects the pixels the fill tool would fill with the same options.
+    pub fn select_similar(
+        &mut self,
+        screen_cords: &Pos2,
+        options: &FillOptions,
+        mode: SelectionMode,
+    ) {
+        let (x, y) = self.camera.screen_cords_to_pixel_cords(screen_cords);
+        if x.is_negative()
+            || y.is_negative()
+            || x as usize >= self.width
+            || y as usize >= self.height
+        {
+            return;
+        }
+        let mask = self.fill_mask((x as usize, y as usize), options);
+        let similar = Selection::from_mask(self.width, self.height, mask);
+        self.combine_selection(similar, mode);
+    }
+
+    pub fn select_all(&mut self) {
+        self.selection = Some(Selection::all(self.width, self.height));
+    }
+
+    /// Selects every pixel that is not selected, or nothing when everything is.
+    pub fn invert_selection(&mut self) {
+        let inverted = match &self.selection {
+            Some(selection) => selection.inverted(),
+            None => Selection::all(self.width, self.height),
+        };
+        self.set_selection(Some(inverted));
+    }
+
+    fn combine_selection(&mut self, selection: Selection, mode: SelectionMode) {
+        let combined = match (&self.selection, mode) {
+            (Some(current), mode) if mode != SelectionMode::Replace => {
+                current.combine(&selection, mode)
+            }
+            // Without a selection nothing is selected yet, though everything can be painted
+            (None, SelectionMode::Subtract | SelectionMode::Intersect) => return,
+            _ => selection,
+        };
+        self.set_selection(Some(combined));
+    }
+
+    /// Position under the cursor in pixels, keeping the fraction of a pixel.
+    pub fn screen_to_pixel_pos(&self, screen_cords: &Pos2) -> Pos2 {
+        let camera = &self.camera;
+        ((*screen_cords - camera.screen_center) / camera.square_size
+            + camera.pixel_center.round().to_vec2())
+        .to_pos2()
+    }
+
+    pub fn pixel_to_screen_pos(&self, pixel_pos: Pos2) -> Pos2 {
+        let camera = &self.camera;
+        camera.screen_center + (pixel_pos - camera.pixel_center.round()) * camera.square_size
+    }
+
+    /// Copies the selected pixels of the active layer.
+    pub fn copy_selection(&self) -> Option<Clipboard> {
+        let selection = self.selection.as_ref()?;
+        let (x1, y1, x2, y2) = selection.get_bounds()?;
+        let cel = self.get_cel(self.active_layer, self.active_frame);
+        let (width, height) = (x2 - x1 + 1, y2 - y1 + 1);
+        let pixels = (0..width * height)
+            .map(|idx| {
+                let (x, y) = (x1 + idx % width, y1 + idx / width);
+                selection.contains(x, y).then(|| cel[x + y * self.width])
+            })
+            .collect();
+        Some(Clipboard {
+            x: x1 as isize,
+            y: y1 as isize,
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// Clears the selected pixels of the active layer.
+    pub fn delete_selection(&mut self) {
+        let Some((x1, y1, x2, y2)) = self.selection.as_ref().and_then(Selection::get_bounds) else {
+            return;
+        };
+        for y in y1..=y2 {
+            for x in x1..=x2 {
+                self.paint_pixel(x as isize, y as isize, Color32::TRANSPARENT);
+            }
+        }
+    }
+
+    /// Pastes copied pixels onto the active layer where they were copied from, and
+    /// selects them so they can be moved right away.
+    pub fn paste(&mut self, clipboard: &Clipboard) {
+        let pasted = self.stamp(clipboard);
+        self.set_selection(Some(pasted));
+    }
+
+    /// Draws pixels over the active layer, ignoring the selection. Returns what was
+    /// drawn as a selection.
+    fn stamp(&mut self, clipboard: &Clipboard) -> Selection {
+        let mut mask = vec![false; self.width * self.height];
+        for (idx, color) in clipboard.pixels.iter().enumerate() {
+            let Some(color) = color else {
+                continue;
+            };
+            let x = clipboard.x + (idx % clipboard.width) as isize;
+            let y = clipboard.y + (idx / clipboard.width) as isize;
+            if x.is_negative() || y.is_negative() {
+                continue;
+            }
+            let (x, y) = (x as usize, y as usize);
+            if let Some(pixel) = self.get_pixel_mut(x, y, self.active_layer) {
+                *pixel = blend_over(*pixel, *color);
+                self.refresh_image_pixel(x, y);
+                mask[x + y * self.width] = true;
+            }
+        }
+        Selection::from_mask(self.width, self.height, mask)
+    }
+
+    /// Flips or turns the selected pixels about the middle of the selection, or the
+    /// whole active layer when nothing is selected.
+    pub fn transform_selection(&mut self, transform: Transform) {
+        self.replace_selected(|lifted| lifted.transformed(transform));
+    }
+
+    /// Rotates the selected pixels, or the whole active layer, clockwise by any angle
+    /// with RotSprite.
+    pub fn rotate_selection(&mut self, degrees: f32) {
+        self.replace_selected(|lifted| rotsprite::rotate(lifted, degrees));
+    }
+
+    /// Stretches the selected pixels, or the whole active layer, over the pixel
+    /// rectangle `(x1, y1, x2, y2)` from `get_transform_bounds`. Neither side grows
+    /// past `MAX_CANVAS_SIZE`.
+    pub fn scale_selection(&mut self, bounds: (isize, isize, isize, isize)) {
+        let (x1, y1, x2, y2) = bounds;
+        let size = |start: isize, end: isize| (end - start + 1).clamp(1, MAX_CANVAS_SIZE as isize);
+        let (width, height) = (size(x1, x2) as usize, size(y1, y2) as usize);
+        self.replace_selected(|lifted| lifted.scaled(x1, y1, width, height));
+    }
+
+    /// Lifts the selected pixels off the active layer and puts down what `transform`
+    /// makes of them instead. The selection follows the pixels.
+    fn replace_selected(&mut self, transform: impl FnOnce(&Clipboard) -> Clipboard) {
+        let lifted = match self.copy_selection() {
+            Some(lifted) => lifted,
+            None if self.selection.is_none() => Clipboard {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+                pixels: self
+                    .get_cel(self.active_layer, self.active_frame)
+                    .iter()
+                    .map(|&color| Some(color))
+                    .collect(),
+            },
+            None => return,
+        };
+
+        let transformed = transform(&lifted);
+        match self.selection.is_some() {
+            true => self.delete_selection(),
+            false => {
+                for y in 0..self.height {
+                    for x in 0..self.width {
+                        self.paint_pixel(x as isize, y as isize, Color32::TRANSPARENT);
                     }
-                } else {
-                    return;
                 }
             }
-            None => return,
+        }
+        let stamped = self.stamp(&transformed);
+        if self.selection.is_some() {
+            self.set_selection(Some(stamped));
+        }
+    }
+
+    /// Pixel rectangle `(x1, y1, x2, y2)` the transforms act on, the selected pixels
+    /// or the whole canvas.
+    pub fn get_transform_bounds(&self) -> (isize, isize, isize, isize) {
+        match self.selection.as_ref().and_then(Selection::get_bounds) {
+            Some((x1, y1, x2, y2)) => (x1 as isize, y1 as isize, x2 as isize, y2 as isize),
+            None => (0, 0, self.width as isize - 1, self.height as isize - 1),
+        }
+    }
+
+    /// Screen rectangles of the handles around `bounds`, going around the corners
+    /// and the middle of each side. Handle `i` moves the sides in `SCALE_HANDLES[i]`.
+    pub fn get_scale_handles(&self, bounds: (isize, isize, isize, isize)) -> Vec<Rect> {
+        let rect = self.bounds_to_screen(bounds);
+        SCALE_HANDLES
+            .iter()
+            .map(|&(hx, hy)| {
+                let center = Pos2::new(
+                    rect.min.x + rect.width() * (hx + 1) as f32 / 2.0,
+                    rect.min.y + rect.height() * (hy + 1) as f32 / 2.0,
+                );
+                Rect::from_center_size(center, Vec2::splat(SCALE_HANDLE_SIZE))
+            })
+            .collect()
+    }
+
+    /// Bounds after dragging a scale handle to the cursor, keeping the opposite side
+    /// in place. With `integer` the size snaps to whole multiples of `original`. Sides
+    /// are at most `MAX_CANVAS_SIZE` long.
+    pub fn drag_scale_handle(
+        &self,
+        original: (isize, isize, isize, isize),
+        handle: usize,
+        screen_cords: &Pos2,
+        integer: bool,
+    ) -> (isize, isize, isize, isize) {
+        let (x1, y1, x2, y2) = original;
+        let (hx, hy) = SCALE_HANDLES[handle];
+        // The pixel corner closest to the cursor
+        let corner = self.screen_to_pixel_pos(screen_cords).round();
+        let (cx, cy) = (corner.x as isize, corner.y as isize);
+
+        let resize = |start: isize, end: isize, side: isize, to: isize| {
+            let size = end - start + 1;
+            let mut new_size = match side {
+                -1 => end - to + 1,
+                1 => to - start,
+                _ => size,
+            }
+            .clamp(1, MAX_CANVAS_SIZE as isize);
+            if integer && side != 0 {
+                let max_factor = (MAX_CANVAS_SIZE as isize / size).max(1);
+                let factor = (new_size as f32 / size as f32).round() as isize;
+                new_size = factor.clamp(1, max_factor) * size;
+            }
+            match side {
+                -1 => (end - new_size + 1, end),
+                _ => (start, start + new_size - 1),
+            }
+        };
+        let (nx1, nx2) = resize(x1, x2, hx, cx);
+        let (ny1, ny2) = resize(y1, y2, hy, cy);
+        (nx1, ny1, nx2, ny2)
+    }
+
+    /// Marching ants around the bounds a scale would stretch the pixels over, with
+    /// its handles.
+    pub fn get_scale_shapes(&self, bounds: (isize, isize, isize, isize), time: f64) -> Vec<Shape> {
+        let rect = self.bounds_to_screen(bounds);
+        let corners = [
+            rect.left_top(),
+            rect.right_top(),
+            rect.right_bottom(),
+            rect.left_bottom(),
+            rect.left_top(),
+        ];
+        let lines: Vec<[Pos2; 2]> = corners.windows(2).map(|w| [w[0], w[1]]).collect();
+        let mut shapes = Self::marching_ants(&lines, time);
+        shapes.extend(self.get_scale_handles(bounds).into_iter().map(|handle| {
+            Shape::from(RectShape::new(
+                handle,
+                Rounding::default(),
+                Color32::WHITE,
+                Stroke::new(1.0, Color32::BLACK),
+            ))
+        }));
+        shapes
+    }
+
+    /// Handles for dragging the symmetry axes, just outside the canvas so grabbing one
+    /// doesn't paint. Handle 0 moves the vertical axis and handle 1 the horizontal one.
+    pub fn get_symmetry_handles(&self) -> [Option<Rect>; 2] {
+        let canvas =
+            self.bounds_to_screen((0, 0, self.width as isize - 1, self.height as isize - 1));
+        let axis = self.symmetry_axis_screen_pos();
+        let offset = SYMMETRY_HANDLE_SIZE;
+        [
+            self.symmetry.mirrors_x().then(|| {
+                let center = Pos2::new(axis.x, canvas.min.y - offset);
+                Rect::from_center_size(center, Vec2::splat(SYMMETRY_HANDLE_SIZE))
+            }),
+            self.symmetry.mirrors_y().then(|| {
+                let center = Pos2::new(canvas.min.x - offset, axis.y);
+                Rect::from_center_size(center, Vec2::splat(SYMMETRY_HANDLE_SIZE))
+            }),
+        ]
+    }
+
+    /// Moves a symmetry axis to the pixel edge or pixel middle closest to the cursor,
+    /// keeping it on the canvas.
+    pub fn drag_symmetry_axis(&mut self, handle: usize, screen_cords: &Pos2) {
+        let pos = self.screen_to_pixel_pos(screen_cords) * 2.0;
+        if handle == 0 {
+            self.symmetry.axis_x = (pos.x.round() as isize).clamp(0, 2 * self.width as isize);
+        } else {
+            self.symmetry.axis_y = (pos.y.round() as isize).clamp(0, 2 * self.height as isize);
+        }
+    }
+
+    /// Lines along the symmetry axes, with the handles for moving them.
+    pub fn get_symmetry_shapes(&self) -> Vec<Shape> {
+        let canvas =
+            self.bounds_to_screen((0, 0, self.width as isize - 1, self.height as isize - 1));
+        let axis = self.symmetry_axis_screen_pos();
+        let stroke = Stroke::new(1.0, SYMMETRY_AXIS_COLOR);
+
+        let mut shapes = Vec::new();
+        if self.symmetry.mirrors_x() {
+            shapes.push(Shape::line_segment(
+                [
+                    Pos2::new(axis.x, canvas.min.y),
+                    Pos2::new(axis.x, canvas.max.y),
+                ],
+                stroke,
+            ));
+        }
+        if self.symmetry.mirrors_y() {
+            shapes.push(Shape::line_segment(
+                [
+                    Pos2::new(canvas.min.x, axis.y),
+                    Pos2::new(canvas.max.x, axis.y),
+                ],
+                stroke,
+            ));
+        }
+        shapes.extend(
+            self.get_symmetry_handles()
+                .into_iter()
+                .flatten()
+                .map(|handle| {
+                    Shape::from(RectShape::new(
+                        handle,
+                        Rounding::default(),
+                        SYMMETRY_AXIS_COLOR,
+                        Stroke::new(1.0, Color32::BLACK),
+                    ))
+                }),
+        );
+        shapes
+    }
+
+    fn symmetry_axis_screen_pos(&self) -> Pos2 {
+        self.pixel_to_screen_pos(Pos2::new(
+            self.symmetry.axis_x as f32 / 2.0,
+            self.symmetry.axis_y as f32 / 2.0,
+        ))
+    }
+
+    fn bounds_to_screen(&self, (x1, y1, x2, y2): (isize, isize, isize, isize)) -> Rect {
+        Rect::from_min_max(
+            self.camera.pixel_cords_to_screen_cords(x1, y1),
+            self.camera.pixel_cords_to_screen_cords(x2 + 1, y2 + 1),
+        )
+    }
+
+    /// Starts moving the selected pixels if the cursor is on one of them, lifting
+    /// them off the active layer. Returns whether a move was started.
+    pub fn begin_move(&mut self, screen_cords: &Pos2) -> bool {
+        let (x, y) = self.camera.screen_cords_to_pixel_cords(screen_cords);
+        let on_selection = x >= 0 && y >= 0 && {
+            let selection = self.selection.as_ref();
+            selection.is_some_and(|s| s.contains(x as usize, y as usize))
+        };
+        let (true, Some(pixels)) = (on_selection, self.copy_selection()) else {
+            return false;
+        };
+
+        self.delete_selection();
+        self.floating = Some(FloatingSelection {
+            pixels,
+            selection: self.selection.clone().unwrap(),
+            base: self.get_cel(self.active_layer, self.active_frame).to_vec(),
+            start: (x, y),
+            offset: (0, 0),
+        });
+        self.move_floating((0, 0));
+        true
+    }
+
+    /// Moves the lifted pixels so that the pixel the move started from is under the
+    /// cursor.
+    pub fn drag_move(&mut self, screen_cords: &Pos2) {
+        let Some(floating) = &self.floating else {
+            return;
+        };
+        let (x, y) = self.camera.screen_cords_to_pixel_cords(screen_cords);
+        let offset = (x - floating.start.0, y - floating.start.1);
+        if offset != floating.offset {
+            self.move_floating(offset);
+        }
+    }
+
+    /// Puts the moved pixels down for good.
+    pub fn end_move(&mut self) {
+        self.floating = None;
+    }
+
+    fn move_floating(&mut self, offset: (isize, isize)) {
+        let Some(floating) = self.floating.take() else {
+            return;
+        };
+        let pixels = &floating.pixels;
+        let positions = |(dx, dy): (isize, isize)| {
+            pixels
+                .pixels
+                .iter()
+                .enumerate()
+                .filter_map(move |(idx, color)| {
+                    let x = pixels.x + (idx % pixels.width) as isize + dx;
+                    let y = pixels.y + (idx / pixels.width) as isize + dy;
+                    color.map(|color| (x, y, color))
+                })
+                .filter(|&(x, y, _)| {
+                    x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height
+                })
+                .map(|(x, y, color)| (x as usize, y as usize, color))
+                .collect::<Vec<_>>()
+        };
+        let (previous, next) = (positions(floating.offset), positions(offset));
+
+        for (x, y, _) in previous {
+            let color = floating.base[x + y * self.width];
+            if let Some(pixel) = self.get_pixel_mut(x, y, self.active_layer) {
+                *pixel = color;
+            }
+            self.refresh_image_pixel(x, y);
+        }
+        for (x, y, color) in next {
+            let under = floating.base[x + y * self.width];
+            if let Some(pixel) = self.get_pixel_mut(x, y, self.active_layer) {
+                *pixel = blend_over(under, color);
+            }
+            self.refresh_image_pixel(x, y);
         }
 
-        // Call recursively on surrounding squares (4 directions)
-        self.flood_fill((x + 1, y), target_color, fill_color);
-        self.flood_fill((x - 1, y), target_color, fill_color);
-        self.flood_fill((x, y + 1), target_color, fill_color);
-        self.flood_fill((x, y - 1), target_color, fill_color);
+        self.selection = Some(floating.selection.translated(offset.0, offset.1));
+        self.floating = Some(FloatingSelection { offset, ..floating });
+    }
+
+    /// Marching ants around the selection. `time` is in seconds and makes the dashes
+    /// crawl along the outline.
+    pub fn get_selection_shapes(&self, time: f64) -> Vec<Shape> {
+        let Some(selection) = &self.selection else {
+            return Vec::new();
+        };
+        let lines: Vec<[Pos2; 2]> = selection
+            .get_outline()
+            .iter()
+            .map(|&((x1, y1), (x2, y2))| {
+                [
+                    self.camera
+                        .pixel_cords_to_screen_cords(x1 as isize, y1 as isize),
+                    self.camera
+                        .pixel_cords_to_screen_cords(x2 as isize, y2 as isize),
+                ]
+            })
+            .collect();
+        Self::marching_ants(&lines, time)
+    }
+
+    /// Marching ants around the rectangle a selection is being dragged out over.
+    pub fn get_marquee_shapes(
+        &self,
+        start_screen_cords: &Pos2,
+        end_screen_cords: &Pos2,
+        time: f64,
+    ) -> Vec<Shape> {
+        let (x1, y1) = self.camera.screen_cords_to_pixel_cords(start_screen_cords);
+        let (x2, y2) = self.camera.screen_cords_to_pixel_cords(end_screen_cords);
+        let (x1, y1, x2, y2) = (x1.min(x2), y1.min(y2), x1.max(x2) + 1, y1.max(y2) + 1);
+        let corners = [(x1, y1), (x2, y1), (x2, y2), (x1, y2), (x1, y1)]
+            .map(|(x, y)| self.camera.pixel_cords_to_screen_cords(x, y));
+        let lines: Vec<[Pos2; 2]> = corners.windows(2).map(|w| [w[0], w[1]]).collect();
+        Self::marching_ants(&lines, time)
+    }
+
+    /// Marching ants along a lasso that is being drawn, from its first point to the
+    /// cursor. Points are in the fractional pixel coordinates from `screen_to_pixel_pos`.
+    pub fn get_lasso_shapes(&self, points: &[Pos2], cursor: Option<Pos2>, time: f64) -> Vec<Shape> {
+        let path: Vec<Pos2> = points
+            .iter()
+            .map(|&p| self.pixel_to_screen_pos(p))
+            .chain(cursor)
+            .collect();
+        let lines: Vec<[Pos2; 2]> = path.windows(2).map(|w| [w[0], w[1]]).collect();
+        Self::marching_ants(&lines, time)
+    }
+
+    fn marching_ants(lines: &[[Pos2; 2]], time: f64) -> Vec<Shape> {
+        let steps = (time / MARCHING_ANTS_STEP).floor() as f32;
+        let offset = steps * MARCHING_ANTS_DASH / 4.0 % (2.0 * MARCHING_ANTS_DASH);
+        let mut shapes: Vec<Shape> = lines
+            .iter()
+            .map(|&line| Shape::line_segment(line, Stroke::new(1.0, Color32::BLACK)))
+            .collect();
+        for line in lines {
+            shapes.extend(Shape::dashed_line_with_offset(
+                line,
+                Stroke::new(1.0, Color32::WHITE),
+                &[MARCHING_ANTS_DASH],
+                &[MARCHING_ANTS_DASH],
+                offset,
+            ));
+        }
+        shapes
     }
 
     pub fn create_state(&self) -> CanvasState {
-        CanvasState::new(
-            self.layers,
-            self.active_layer,
-            self.layer_names.clone(),
-            self.layer_name_cnt,
-            self.layers_to_show.clone(),
-            self.pixels.clone(),
-            self.squares.clone(),
+        CanvasState {
+            layers: self.layers,
+            active_layer: self.active_layer,
+            layer_names: self.layer_names.clone(),
+            layer_name_cnt: self.layer_name_cnt,
+            layers_to_show: self.layers_to_show.clone(),
+            frames: self.frames,
+            active_frame: self.active_frame,
+            frame_durations: self.frame_durations.clone(),
+            pixels: self.pixels.clone(),
+        }
+    }
+
+    /// Builds a canvas from a state that did not come from a live canvas, e.g. a
+    /// project file.
+    pub fn from_state(width: usize, height: usize, state: &CanvasState) -> Self {
+        let mut canvas = Self::new(width, height);
+        canvas.layers = state.layers;
+        canvas.active_layer = state.active_layer;
+        canvas.layer_names = state.layer_names.clone();
+        canvas.layer_name_cnt = state.layer_name_cnt;
+        canvas.layers_to_show = state.layers_to_show.clone();
+        canvas.frames = state.frames;
+        canvas.active_frame = state.active_frame;
+        canvas.frame_durations = state.frame_durations.clone();
+        canvas.pixels = state.pixels.clone();
+
+        canvas.forget_edits();
+        canvas.update_image();
+        canvas
+    }
+}
+
+/// Source-over compositing of premultiplied colors.
+pub fn blend_over(dst: Color32, src: Color32) -> Color32 {
+    let inv_alpha = 255 - src.a() as u16;
+    let channel = |s: u8, d: u8| s.saturating_add(((d as u16 * inv_alpha + 127) / 255) as u8);
+    Color32::from_rgba_premultiplied(
+        channel(src.r(), dst.r()),
+        channel(src.g(), dst.g()),
+        channel(src.b(), dst.b()),
+        channel(src.a(), dst.a()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(canvas: &Canvas) -> (Vec<Color32>, CanvasMeta, Option<Selection>) {
+        (
+            canvas.pixels.clone(),
+            canvas.meta(),
+            canvas.selection.clone(),
         )
     }
 
-    pub fn load_state(&mut self, state: &CanvasState) {
-        self.layers = state.layers;
-        self.active_layer = state.active_layer;
-        self.layer_names = state.layer_names.clone();
-        self.layer_name_cnt = state.layer_name_cnt;
-        self.layers_to_show = state.layers_to_show.clone();
-        self.pixels = state.pixels.clone();
-        self.squares = state.squares.clone();
+    /// The image a canvas shows, composited from scratch.
+    fn composited(canvas: &mut Canvas) -> Vec<Color32> {
+        let image = canvas.image.clone();
+        canvas.update_image();
+        std::mem::replace(&mut canvas.image, image)
+    }
 
-        self.update_squares();
+    /// Reverting and reapplying `change` takes the canvas back and forth between
+    /// `before` and where it is now, exactly.
+    fn check_change(
+        canvas: &mut Canvas,
+        change: &Change,
+        before: &(Vec<Color32>, CanvasMeta, Option<Selection>),
+    ) {
+        let after = snapshot(canvas);
+        canvas.revert_change(change);
+        assert_eq!(snapshot(canvas), *before);
+        let image = composited(canvas);
+        assert_eq!(canvas.image, image);
+        canvas.apply_change(change);
+        assert_eq!(snapshot(canvas), after);
+        let image = composited(canvas);
+        assert_eq!(canvas.image, image);
+    }
+
+    #[test]
+    fn nothing_changed_takes_no_change() {
+        let mut canvas = Canvas::new(8, 8);
+        assert!(canvas.take_change(String::from("Nothing")).is_none());
+        // Painting a pixel the color it already is changes nothing either
+        canvas.paint_pixel(1, 1, Color32::TRANSPARENT);
+        assert!(canvas.take_change(String::from("Nothing")).is_none());
+    }
+
+    #[test]
+    fn pixel_changes_revert_and_apply() {
+        // Large enough to span several history chunks
+        let mut canvas = Canvas::new(100, 90);
+        let before = snapshot(&canvas);
+        for i in 0..90 {
+            canvas.paint_pixel(i, i, Color32::RED);
+            canvas.paint_pixel(99 - i, i, Color32::from_rgb(0, 0, 255));
+        }
+        canvas.paint_pixel(50, 0, Color32::WHITE);
+        let change = canvas.take_change(String::from("Paint")).unwrap();
+        assert_eq!(change.before, before.1);
+        check_change(&mut canvas, &change, &before);
+    }
+
+    #[test]
+    fn layer_and_frame_changes_revert_and_apply() {
+        let mut canvas = Canvas::new(16, 12);
+        canvas.paint_pixel(3, 4, Color32::RED);
+        canvas.take_change(String::from("Paint")).unwrap();
+
+        // Pixel edits mixed in between layer and frame edits, as one change
+        let before = snapshot(&canvas);
+        canvas.add_layer();
+        canvas.paint_pixel(5, 5, Color32::WHITE);
+        canvas.add_frame();
+        canvas.paint_pixel(6, 7, Color32::from_rgb(0, 255, 0));
+        canvas.duplicate_frame();
+        canvas.move_frame(0, 2);
+        canvas.delete_layer(0);
+        canvas.paint_pixel(0, 0, Color32::RED);
+        let change = canvas.take_change(String::from("Everything")).unwrap();
+        check_change(&mut canvas, &change, &before);
+    }
+
+    #[test]
+    fn changes_revert_in_order() {
+        let mut canvas = Canvas::new(10, 10);
+        let mut states = vec![snapshot(&canvas)];
+        let mut changes = Vec::new();
+        for i in 0..5 {
+            canvas.paint_pixel(i, i, Color32::RED);
+            if i % 2 == 1 {
+                canvas.add_frame();
+            }
+            changes.push(canvas.take_change(i.to_string()).unwrap());
+            states.push(snapshot(&canvas));
+        }
+
+        for (change, state) in changes.iter().zip(&states).rev() {
+            canvas.revert_change(change);
+            assert_eq!(snapshot(&canvas), *state);
+        }
+        for (change, state) in changes.iter().zip(&states[1..]) {
+            canvas.apply_change(change);
+            assert_eq!(snapshot(&canvas), *state);
+        }
+    }
+
+    /// The onion skin a canvas shows, composited from scratch.
+    fn composited_onion(canvas: &mut Canvas) -> Vec<Color32> {
+        let onion_image = canvas.onion_image.clone();
+        canvas.update_onion_image();
+        std::mem::replace(&mut canvas.onion_image, onion_image)
+    }
+
+    fn onion_skinned_canvas() -> Canvas {
+        let mut canvas = Canvas::new(8, 8);
+        canvas.add_frame();
+        canvas.add_frame();
+        canvas.set_onion_skin(OnionSkin {
+            enabled: true,
+            ..OnionSkin::default()
+        });
+        canvas.take_change(String::from("Add frames")).unwrap();
+        canvas
+    }
+
+    #[test]
+    fn undoing_on_another_frame_shows_that_frame() {
+        let mut canvas = onion_skinned_canvas();
+        canvas.set_active_frame(1);
+        canvas.paint_pixel(2, 3, Color32::RED);
+        let change = canvas.take_change(String::from("Paint")).unwrap();
+
+        canvas.set_active_frame(0);
+        canvas.revert_change(&change);
+        assert_eq!(canvas.get_active_frame(), 1);
+        assert_eq!(canvas.get_cel(0, 1)[2 + 3 * 8], Color32::TRANSPARENT);
+        let (image, onion_image) = (composited(&mut canvas), composited_onion(&mut canvas));
+        assert_eq!(canvas.image, image);
+        assert_eq!(canvas.onion_image, onion_image);
+
+        canvas.set_active_frame(2);
+        canvas.apply_change(&change);
+        assert_eq!(canvas.get_active_frame(), 1);
+        assert_eq!(canvas.get_cel(0, 1)[2 + 3 * 8], Color32::RED);
+        let (image, onion_image) = (composited(&mut canvas), composited_onion(&mut canvas));
+        assert_eq!(canvas.image, image);
+        assert_eq!(canvas.onion_image, onion_image);
+    }
+
+    #[test]
+    fn undoing_on_several_frames_updates_the_onion_skin() {
+        let mut canvas = onion_skinned_canvas();
+        canvas.set_active_frame(1);
+        canvas.paint_pixel(2, 3, Color32::RED);
+        canvas.set_active_frame(0);
+        canvas.paint_pixel(4, 4, Color32::WHITE);
+        let change = canvas.take_change(String::from("Paint")).unwrap();
+        assert_ne!(canvas.onion_image, vec![Color32::TRANSPARENT; 64]);
+
+        // The shown frame was painted on too, so it stays
+        canvas.revert_change(&change);
+        assert_eq!(canvas.get_active_frame(), 0);
+        assert_eq!(canvas.onion_image, vec![Color32::TRANSPARENT; 64]);
+        let image = composited(&mut canvas);
+        assert_eq!(canvas.image, image);
+
+        canvas.apply_change(&change);
+        assert_eq!(canvas.get_active_frame(), 0);
+        let (image, onion_image) = (composited(&mut canvas), composited_onion(&mut canvas));
+        assert_eq!(canvas.image, image);
+        assert_eq!(canvas.onion_image, onion_image);
+    }
+
+    #[test]
+    fn switching_layers_and_frames_is_not_recorded() {
+        let mut canvas = Canvas::new(8, 8);
+        canvas.add_layer();
+        canvas.add_frame();
+        canvas.take_change(String::from("Add")).unwrap();
+
+        canvas.set_active_layer(1);
+        canvas.set_active_frame(0);
+        assert!(canvas.take_change(String::from("Switch")).is_none());
+
+        canvas.paint_pixel(1, 1, Color32::RED);
+        let change = canvas.take_change(String::from("Paint")).unwrap();
+        canvas.set_active_layer(0);
+        canvas.revert_change(&change);
+        assert_eq!(canvas.get_active_layer(), 0);
+        assert_eq!(canvas.get_active_frame(), 0);
+        canvas.apply_change(&change);
+        assert_eq!(canvas.get_active_layer(), 0);
+        assert_eq!(canvas.get_cel(1, 0)[1 + 8], Color32::RED);
+    }
+
+    #[test]
+    fn scaling_stops_at_the_largest_canvas() {
+        let mut canvas = Canvas::new(8, 8);
+        let far = Pos2::new(1e9, 1e9);
+        for integer in [false, true] {
+            let (x1, y1, x2, y2) = canvas.drag_scale_handle((0, 0, 2, 2), 4, &far, integer);
+            assert!(x2 - x1 < MAX_CANVAS_SIZE as isize);
+            assert!(y2 - y1 < MAX_CANVAS_SIZE as isize);
+        }
+
+        canvas.set_selection(Some(Selection::rect(8, 8, (0, 0), (2, 2))));
+        canvas.scale_selection((0, 0, isize::MAX / 2, isize::MAX / 2));
+        assert!(canvas.take_change(String::from("Scale")).is_some());
+    }
+
+    #[test]
+    fn selection_changes_revert_and_apply() {
+        let mut canvas = Canvas::new(12, 8);
+        for i in 0..8 {
+            canvas.paint_pixel(i, i, Color32::RED);
+        }
+        canvas.take_change(String::from("Paint")).unwrap();
+        let before = snapshot(&canvas);
+        canvas.set_selection(Some(Selection::rect(12, 8, (2, 2), (5, 6))));
+        let change = canvas.take_change(String::from("Select")).unwrap();
+        check_change(&mut canvas, &change, &before);
+
+        let before = snapshot(&canvas);
+        canvas.invert_selection();
+        canvas.delete_selection();
+        let change = canvas.take_change(String::from("Delete")).unwrap();
+        check_change(&mut canvas, &change, &before);
     }
 }
@@ -0,0 +1,360 @@
+use crate::change_manager::CanvasState;
+use crate::export::{ExportFormat, ExportOptions};
+use crate::model::{Canvas, DEFAULT_FRAME_DURATION};
+use egui::Color32;
+use std::io::{self, Read, Write};
+
+pub const PROJECT_EXTENSION: &str = "ruxel";
+
+const MAGIC: &[u8; 6] = b"RUXEL\0";
+
+/// Bump this whenever the layout changes. Readers must keep accepting every older
+/// version, filling in defaults for the fields that version did not have yet.
+pub const PROJECT_VERSION: u32 = 4;
+
+/// Guards against allocating absurd amounts of memory for corrupt files.
+const MAX_PIXELS: usize = 1 << 28;
+const MAX_NAME_LEN: usize = 1 << 16;
+
+/// Everything that is stored in a `.ruxel` file.
+pub struct Project {
+    pub width: usize,
+    pub height: usize,
+    pub state: CanvasState,
+    pub palette: Vec<Color32>,
+    pub export_options: ExportOptions,
+}
+
+impl Project {
+    pub fn into_canvas(self) -> (Canvas, Vec<Color32>) {
+        let mut canvas = Canvas::from_state(self.width, self.height, &self.state);
+        canvas.set_export_options(self.export_options);
+        (canvas, self.palette)
+    }
+}
+
+/// Layout (all integers little endian):
+///
+/// ```text
+/// magic "RUXEL\0", version u32,
+/// width u32, height u32, layers u32, active_layer u32, layer_name_cnt u32,
+/// layers * (name_len u32, name utf8), layers * visible u8,
+/// palette_len u32, palette_len * rgba,
+/// since v3: frames u32, active_frame u32, frames * duration_ms u32,
+/// frames * layers * width * height * rgba (a single frame before v3),
+/// since v2: export scale u32, format u8, include_hidden u8, fill_background u8,
+///           background rgba, crop_to_content u8,
+/// since v4: loop_count u16
+/// ```
+///
+/// Colors are stored premultiplied, exactly as `Color32` holds them, so nothing is
+/// lost on a round trip.
+pub fn write_project<W: Write>(
+    writer: &mut W,
+    canvas: &Canvas,
+    palette: &[Color32],
+) -> io::Result<()> {
+    let state = canvas.create_state();
+
+    writer.write_all(MAGIC)?;
+    write_u32(writer, PROJECT_VERSION)?;
+
+    write_usize(writer, canvas.get_width())?;
+    write_usize(writer, canvas.get_height())?;
+    write_usize(writer, state.layers)?;
+    write_usize(writer, state.active_layer)?;
+    write_usize(writer, state.layer_name_cnt)?;
+    for name in &state.layer_names {
+        write_usize(writer, name.len())?;
+        writer.write_all(name.as_bytes())?;
+    }
+    for to_show in &state.layers_to_show {
+        writer.write_all(&[*to_show as u8])?;
+    }
+
+    write_usize(writer, palette.len())?;
+    for color in palette {
+        writer.write_all(&color.to_array())?;
+    }
+
+    write_usize(writer, state.frames)?;
+    write_usize(writer, state.active_frame)?;
+    for duration in &state.frame_durations {
+        write_u32(writer, *duration)?;
+    }
+
+    let pixel_bytes: Vec<u8> = state.pixels.iter().flat_map(|c| c.to_array()).collect();
+    writer.write_all(&pixel_bytes)?;
+
+    let options = canvas.get_export_options();
+    write_u32(writer, options.scale)?;
+    writer.write_all(&[
+        options.format.to_id(),
+        options.include_hidden as u8,
+        options.fill_background as u8,
+    ])?;
+    writer.write_all(&options.background.to_array())?;
+    writer.write_all(&[options.crop_to_content as u8])?;
+    writer.write_all(&options.loop_count.to_le_bytes())
+}
+
+pub fn read_project<R: Read>(reader: &mut R) -> io::Result<Project> {
+    let mut magic = [0u8; 6];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("Not a Ruxel project"));
+    }
+
+    let version = read_u32(reader)?;
+    if version == 0 || version > PROJECT_VERSION {
+        return Err(invalid_data(&format!(
+            "Project version {} is not supported by this build",
+            version
+        )));
+    }
+
+    let width = read_usize(reader)?;
+    let height = read_usize(reader)?;
+    let layers = read_usize(reader)?;
+    if width == 0 || height == 0 || layers == 0 {
+        return Err(invalid_data("Project has an empty canvas"));
+    }
+    // Every layer has at least one frame of pixels, so this bounds the allocations
+    // below before the pixel count itself is known
+    width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(layers))
+        .filter(|n| *n <= MAX_PIXELS)
+        .ok_or_else(|| invalid_data("Project is too large"))?;
+    let active_layer = read_usize(reader)?.min(layers - 1);
+    let layer_name_cnt = read_usize(reader)?;
+
+    let mut layer_names = Vec::with_capacity(layers);
+    for _ in 0..layers {
+        let len = read_usize(reader)?;
+        if len > MAX_NAME_LEN {
+            return Err(invalid_data("Bad layer name"));
+        }
+        let mut name = vec![0u8; len];
+        reader.read_exact(&mut name)?;
+        layer_names.push(String::from_utf8(name).map_err(|_| invalid_data("Bad layer name"))?);
+    }
+
+    let mut layers_to_show = vec![0u8; layers];
+    reader.read_exact(&mut layers_to_show)?;
+    let layers_to_show = layers_to_show.into_iter().map(|b| b != 0).collect();
+
+    let palette_len = read_usize(reader)?;
+    let mut palette = Vec::with_capacity(palette_len.min(256));
+    for _ in 0..palette_len {
+        palette.push(read_color(reader)?);
+    }
+
+    let (frames, active_frame, frame_durations) = if version >= 3 {
+        let frames = read_usize(reader)?;
+        if frames == 0 {
+            return Err(invalid_data("Project has no frames"));
+        }
+        let active_frame = read_usize(reader)?.min(frames - 1);
+        let mut frame_durations = Vec::with_capacity(frames.min(1024));
+        for _ in 0..frames {
+            frame_durations.push(read_u32(reader)?);
+        }
+        (frames, active_frame, frame_durations)
+    } else {
+        (1, 0, vec![DEFAULT_FRAME_DURATION])
+    };
+
+    let pixel_cnt = width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(layers))
+        .and_then(|n| n.checked_mul(frames))
+        .filter(|n| *n <= MAX_PIXELS)
+        .ok_or_else(|| invalid_data("Project is too large"))?;
+    let mut pixel_bytes = vec![0u8; pixel_cnt * 4];
+    reader.read_exact(&mut pixel_bytes)?;
+    let pixels = pixel_bytes
+        .chunks_exact(4)
+        .map(|c| Color32::from_rgba_premultiplied(c[0], c[1], c[2], c[3]))
+        .collect();
+
+    let export_options = if version >= 2 {
+        read_export_options(reader, version)?
+    } else {
+        ExportOptions::default()
+    };
+
+    Ok(Project {
+        width,
+        height,
+        state: CanvasState {
+            layers,
+            active_layer,
+            layer_names,
+            layer_name_cnt,
+            layers_to_show,
+            frames,
+            active_frame,
+            frame_durations,
+            pixels,
+        },
+        palette,
+        export_options,
+    })
+}
+
+fn read_export_options<R: Read>(reader: &mut R, version: u32) -> io::Result<ExportOptions> {
+    let scale = read_u32(reader)?;
+    let mut flags = [0u8; 3];
+    reader.read_exact(&mut flags)?;
+    let background = read_color(reader)?;
+    let mut crop_to_content = [0u8; 1];
+    reader.read_exact(&mut crop_to_content)?;
+    let mut loop_count = [0u8; 2];
+    if version >= 4 {
+        reader.read_exact(&mut loop_count)?;
+    }
+
+    Ok(ExportOptions {
+        scale,
+        format: ExportFormat::from_id(flags[0]).unwrap_or_default(),
+        include_hidden: flags[1] != 0,
+        fill_background: flags[2] != 0,
+        background,
+        crop_to_content: crop_to_content[0] != 0,
+        loop_count: u16::from_le_bytes(loop_count),
+    })
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_usize<W: Write>(writer: &mut W, value: usize) -> io::Result<()> {
+    let value = u32::try_from(value).map_err(|_| invalid_data("Value too large"))?;
+    write_u32(writer, value)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_usize<R: Read>(reader: &mut R) -> io::Result<usize> {
+    read_u32(reader).map(|v| v as usize)
+}
+
+fn read_color<R: Read>(reader: &mut R) -> io::Result<Color32> {
+    let mut c = [0u8; 4];
+    reader.read_exact(&mut c)?;
+    Ok(Color32::from_rgba_premultiplied(c[0], c[1], c[2], c[3]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_project() -> (Canvas, Vec<Color32>) {
+        let mut canvas = Canvas::new(7, 5);
+        canvas.add_layer();
+        canvas.add_layer();
+        canvas.add_frame();
+        canvas.add_frame();
+        for (i, color) in [
+            Color32::RED,
+            Color32::from_rgba_premultiplied(10, 20, 30, 40),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            canvas.set_active_frame(i + 1);
+            canvas.set_active_layer(i);
+            canvas.paint_pixel(i as isize, 4 - i as isize, color);
+            canvas.paint_pixel(6, 0, Color32::WHITE);
+        }
+        *canvas.get_frame_duration_mut(2) = 250;
+        canvas.get_layers_to_show_mut()[1] = false;
+
+        let mut state = canvas.create_state();
+        state.layer_names[2] = String::from("Ink ✏");
+        let mut canvas = Canvas::from_state(7, 5, &state);
+        canvas.set_export_options(ExportOptions {
+            scale: 3,
+            format: ExportFormat::AnimatedGif,
+            include_hidden: true,
+            fill_background: true,
+            background: Color32::from_rgb(1, 2, 3),
+            crop_to_content: true,
+            loop_count: 2,
+        });
+
+        let palette = vec![Color32::BLACK, Color32::TRANSPARENT, Color32::GOLD];
+        (canvas, palette)
+    }
+
+    fn write(canvas: &Canvas, palette: &[Color32]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_project(&mut bytes, canvas, palette).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let (canvas, palette) = sample_project();
+        let project = read_project(&mut write(&canvas, &palette).as_slice()).unwrap();
+
+        assert_eq!((project.width, project.height), (7, 5));
+        assert_eq!(project.state, canvas.create_state());
+        assert_eq!(project.palette, palette);
+        assert_eq!(project.export_options, *canvas.get_export_options());
+
+        // And reading it into a canvas loses nothing either
+        let (read_canvas, read_palette) = project.into_canvas();
+        assert_eq!(write(&read_canvas, &read_palette), write(&canvas, &palette));
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let (canvas, palette) = sample_project();
+        let bytes = write(&canvas, &palette);
+        for len in 0..bytes.len() {
+            let err = read_project(&mut &bytes[..len]).err().unwrap();
+            assert_eq!(
+                err.kind(),
+                io::ErrorKind::UnexpectedEof,
+                "truncated to {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn absurd_sizes_are_an_error() {
+        let bytes = write(&Canvas::new(2, 2), &[]);
+        // width, height and layers follow the magic and the version
+        for (offset, value) in [
+            (10, u32::MAX),
+            (14, u32::MAX),
+            (18, u32::MAX),
+            (18, 1 << 27),
+        ] {
+            let mut bytes = bytes.clone();
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            let err = read_project(&mut bytes.as_slice()).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn not_a_project_is_an_error() {
+        let mut bytes = write(&Canvas::new(2, 2), &[]);
+        bytes[0] = b'P';
+        let err = read_project(&mut bytes.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,283 @@
+/// Pixels of a one pixel wide line between two pixels, both included, with
+/// Bresenham's algorithm. Every step moves to a neighbouring pixel and no pixel is
+/// ever doubled up, which is what pixel art lines should look like.
+pub fn line(start: (isize, isize), end: (isize, isize)) -> Vec<(isize, isize)> {
+    let (mut x, mut y) = start;
+    let (dx, dy) = ((end.0 - x).abs(), -(end.1 - y).abs());
+    let (step_x, step_y) = ((end.0 - x).signum(), (end.1 - y).signum());
+    let mut error = dx + dy;
+
+    let mut points = Vec::with_capacity(dx.max(-dy) as usize + 1);
+    loop {
+        points.push((x, y));
+        if (x, y) == end {
+            return points;
+        }
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        // Ties go to x alone, so 2:1 slopes come out as even pairs of pixels
+        if doubled < dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+}
+
+/// Directions lines snap to, all of which make even steps in pixel art: straight,
+/// diagonal, and the 2:1 slopes.
+const SNAP_DIRECTIONS: [(isize, isize); 8] = [
+    (1, 0),
+    (2, 1),
+    (1, 1),
+    (1, 2),
+    (0, 1),
+    (-1, 2),
+    (-1, 1),
+    (-2, 1),
+];
+
+/// Moves the end of a line so the line runs in the closest of the snapping directions,
+/// ending on a whole number of steps.
+pub fn snap_line(start: (isize, isize), end: (isize, isize)) -> (isize, isize) {
+    let (vx, vy) = ((end.0 - start.0) as f32, (end.1 - start.1) as f32);
+    if vx == 0.0 && vy == 0.0 {
+        return end;
+    }
+
+    // Each direction also covers its opposite, so compare the angle between lines
+    let alignment = |(dx, dy): (isize, isize)| {
+        let (dx, dy) = (dx as f32, dy as f32);
+        (vx * dx + vy * dy).abs() / (dx * dx + dy * dy).sqrt()
+    };
+    let (dx, dy) = SNAP_DIRECTIONS
+        .into_iter()
+        .max_by(|&a, &b| alignment(a).total_cmp(&alignment(b)))
+        .unwrap();
+
+    let steps = ((vx * dx as f32 + vy * dy as f32) / (dx * dx + dy * dy) as f32).round() as isize;
+    (start.0 + steps * dx, start.1 + steps * dy)
+}
+
+/// Offsets of the pixels in a round brush, the same shape the draw tool paints.
+pub fn disk(radius: usize) -> Vec<(isize, isize)> {
+    let radius = radius.max(1) as isize;
+    let mut offsets = Vec::new();
+    for y in -radius..=radius {
+        for x in -radius..=radius {
+            if x * x + y * y < radius * radius {
+                offsets.push((x, y));
+            }
+        }
+    }
+    offsets
+}
+
+/// Every pixel covered by a round brush of `radius` put down at each of `points`,
+/// each pixel only once.
+pub fn stamp(points: &[(isize, isize)], radius: usize) -> Vec<(isize, isize)> {
+    let brush = disk(radius);
+    let mut pixels: Vec<(isize, isize)> = points
+        .iter()
+        .flat_map(|&(x, y)| brush.iter().map(move |&(bx, by)| (x + bx, y + by)))
+        .collect();
+    pixels.sort_unstable();
+    pixels.dedup();
+    pixels
+}
+
+/// Moves the end of a drag so the rectangle between it and the start is a square, the
+/// size of its longer side.
+pub fn square_end(start: (isize, isize), end: (isize, isize)) -> (isize, isize) {
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let side = dx.abs().max(dy.abs());
+    let sign = |d: isize| if d < 0 { -1 } else { 1 };
+    (start.0 + side * sign(dx), start.1 + side * sign(dy))
+}
+
+/// Outline of the ellipse that fits the pixel rectangle `(x1, y1, x2, y2)`, with the
+/// midpoint ellipse algorithm. Working from the rectangle instead of a center and
+/// radii lets even sizes have a two pixel wide middle, so the outline stays
+/// symmetric at any size.
+pub fn ellipse(bounds: (isize, isize, isize, isize)) -> Vec<(isize, isize)> {
+    let (mut x0, mut y0, mut x1, mut y1) = bounds;
+    // One pixel wide or tall ellipses are just lines
+    if x0 == x1 || y0 == y1 {
+        return (y0..=y1)
+            .flat_map(|y| (x0..=x1).map(move |x| (x, y)))
+            .collect();
+    }
+    let a = (x1 - x0) as i64;
+    let b = (y1 - y0) as i64;
+    let b1 = b & 1;
+
+    // The error terms are the midpoint criterion scaled up to stay in integers
+    let mut dx = 4 * (1 - a) * b * b;
+    let mut dy = 4 * (b1 + 1) * a * a;
+    let mut error = dx + dy + b1 * a * a;
+    y0 += ((b + 1) / 2) as isize;
+    y1 = y0 - b1 as isize;
+    let (step_x, step_y) = (8 * b * b, 8 * a * a);
+
+    let mut points = Vec::new();
+    loop {
+        points.extend([(x1, y0), (x0, y0), (x0, y1), (x1, y1)]);
+        let doubled = 2 * error;
+        if doubled <= dy {
+            y0 += 1;
+            y1 -= 1;
+            dy += step_y;
+            error += dy;
+        }
+        if doubled >= dx || 2 * error > dy {
+            x0 += 1;
+            x1 -= 1;
+            dx += step_x;
+            error += dx;
+        }
+        if x0 > x1 {
+            break;
+        }
+    }
+    // Very flat ellipses stop before reaching their ends
+    while ((y0 - y1) as i64) <= b {
+        points.extend([(x0 - 1, y0), (x1 + 1, y0), (x0 - 1, y1), (x1 + 1, y1)]);
+        y0 += 1;
+        y1 -= 1;
+    }
+
+    points.sort_unstable();
+    points.dedup();
+    points
+}
+
+/// Every pixel inside the ellipse that fits `bounds`, outline included.
+pub fn filled_ellipse(bounds: (isize, isize, isize, isize)) -> Vec<(isize, isize)> {
+    fill_outline(&ellipse(bounds), bounds)
+}
+
+/// Outline of the ellipse that fits `bounds`, `width` pixels thick towards the inside.
+pub fn thick_ellipse(bounds: (isize, isize, isize, isize), width: usize) -> Vec<(isize, isize)> {
+    if width <= 1 {
+        return ellipse(bounds);
+    }
+    let inner = shrink(bounds, width);
+    let hole = inner.map(filled_ellipse).unwrap_or_default();
+    subtract(filled_ellipse(bounds), &hole)
+}
+
+/// Outline of the pixel rectangle `bounds` with its corners rounded off by quarter
+/// circles of `radius`. The corners are the quarters of a midpoint circle, so they
+/// match the ellipse tool pixel for pixel.
+pub fn rounded_rect(bounds: (isize, isize, isize, isize), radius: usize) -> Vec<(isize, isize)> {
+    let (x1, y1, x2, y2) = bounds;
+    // The corners can at most meet in the middle
+    let radius = (radius as isize).min((x2 - x1) / 2).min((y2 - y1) / 2);
+
+    let corner = |x: isize, y: isize| {
+        let x = if x <= radius {
+            x1 + x
+        } else {
+            x2 - 2 * radius + x
+        };
+        let y = if y <= radius {
+            y1 + y
+        } else {
+            y2 - 2 * radius + y
+        };
+        (x, y)
+    };
+    let mut points: Vec<(isize, isize)> = ellipse((0, 0, 2 * radius, 2 * radius))
+        .into_iter()
+        .map(|(x, y)| corner(x, y))
+        .collect();
+    for x in (x1 + radius)..=(x2 - radius) {
+        points.extend([(x, y1), (x, y2)]);
+    }
+    for y in (y1 + radius)..=(y2 - radius) {
+        points.extend([(x1, y), (x2, y)]);
+    }
+
+    points.sort_unstable();
+    points.dedup();
+    points
+}
+
+/// Every pixel inside the rounded rectangle, outline included.
+pub fn filled_rounded_rect(
+    bounds: (isize, isize, isize, isize),
+    radius: usize,
+) -> Vec<(isize, isize)> {
+    fill_outline(&rounded_rect(bounds, radius), bounds)
+}
+
+/// Outline of a rounded rectangle, `width` pixels thick towards the inside. The inside
+/// edge has its corners rounded by as much less as the outline is thick.
+pub fn thick_rounded_rect(
+    bounds: (isize, isize, isize, isize),
+    radius: usize,
+    width: usize,
+) -> Vec<(isize, isize)> {
+    if width <= 1 {
+        return rounded_rect(bounds, radius);
+    }
+    let inner = shrink(bounds, width);
+    let hole = inner
+        .map(|inner| filled_rounded_rect(inner, radius.saturating_sub(width)))
+        .unwrap_or_default();
+    subtract(filled_rounded_rect(bounds, radius), &hole)
+}
+
+/// The pixels of `points` that are not in the sorted `removed`.
+pub fn subtract(points: Vec<(isize, isize)>, removed: &[(isize, isize)]) -> Vec<(isize, isize)> {
+    points
+        .into_iter()
+        .filter(|point| removed.binary_search(point).is_err())
+        .collect()
+}
+
+/// `bounds` made smaller by `width` on every side, if anything is left.
+fn shrink(
+    (x1, y1, x2, y2): (isize, isize, isize, isize),
+    width: usize,
+) -> Option<(isize, isize, isize, isize)> {
+    let width = width as isize;
+    let inner = (x1 + width, y1 + width, x2 - width, y2 - width);
+    (inner.0 <= inner.2 && inner.1 <= inner.3).then_some(inner)
+}
+
+/// Fills in a convex outline, each row from its leftmost to its rightmost pixel.
+fn fill_outline(
+    outline: &[(isize, isize)],
+    (_, y1, _, y2): (isize, isize, isize, isize),
+) -> Vec<(isize, isize)> {
+    let mut rows = vec![(isize::MAX, isize::MIN); (y2 - y1 + 1) as usize];
+    for &(x, y) in outline {
+        let row = &mut rows[(y - y1) as usize];
+        *row = (row.0.min(x), row.1.max(x));
+    }
+    let mut points: Vec<(isize, isize)> = rows
+        .iter()
+        .enumerate()
+        .flat_map(|(row, &(from, to))| (from..=to).map(move |x| (x, y1 + row as isize)))
+        .collect();
+    points.sort_unstable();
+    points
+}
+
+/// Joins pixels into columns `(x, y1, y2)` of neighbouring pixels, both ends included.
+pub fn columns(mut pixels: Vec<(isize, isize)>) -> Vec<(isize, isize, isize)> {
+    pixels.sort_unstable();
+    pixels.dedup();
+
+    let mut columns: Vec<(isize, isize, isize)> = Vec::new();
+    for (x, y) in pixels {
+        match columns.last_mut() {
+            Some((cx, _, y2)) if *cx == x && *y2 + 1 == y => *y2 = y,
+            _ => columns.push((x, y, y)),
+        }
+    }
+    columns
+}
@@ -0,0 +1,99 @@
+use crate::selection::Clipboard;
+use egui::Color32;
+
+/// Times the pixels are doubled with Scale2x before rotating, 3 giving 8x.
+const UPSCALE_PASSES: u32 = 3;
+/// Most pixels the upscaled image may have. Larger images get fewer Scale2x passes,
+/// which only matters for images far bigger than a sprite.
+const MAX_UPSCALED_PIXELS: usize = 1 << 26;
+
+type Pixels = Vec<Option<Color32>>;
+
+/// Rotates pixels clockwise by any angle with RotSprite: the pixels are upscaled 8x
+/// with Scale2x, which rounds off jagged diagonals without inventing new colors, then
+/// rotated and sampled back down to their original resolution. The rotated pixels are
+/// centered on the original ones.
+pub fn rotate(clipboard: &Clipboard, degrees: f32) -> Clipboard {
+    let (width, height) = (clipboard.width, clipboard.height);
+
+    let mut passes = 0;
+    let mut upscaled = clipboard.pixels.clone();
+    while passes < UPSCALE_PASSES && upscaled.len() * 4 <= MAX_UPSCALED_PIXELS {
+        upscaled = scale2x(&upscaled, width << passes, height << passes);
+        passes += 1;
+    }
+    let factor = (1 << passes) as f32;
+    let upscaled_width = width << passes;
+
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    // The rotated rectangle has to fit, so the result is usually larger
+    let new_width = (width as f32 * cos.abs() + height as f32 * sin.abs() - 0.001).ceil() as usize;
+    let new_height = (width as f32 * sin.abs() + height as f32 * cos.abs() - 0.001).ceil() as usize;
+    let (new_width, new_height) = (new_width.max(1), new_height.max(1));
+
+    let pixels = (0..new_width * new_height)
+        .map(|idx| {
+            // Turn the middle of each new pixel back onto the upscaled image
+            let dx = (idx % new_width) as f32 + 0.5 - new_width as f32 / 2.0;
+            let dy = (idx / new_width) as f32 + 0.5 - new_height as f32 / 2.0;
+            let sx = (cos * dx + sin * dy + width as f32 / 2.0) * factor;
+            let sy = (-sin * dx + cos * dy + height as f32 / 2.0) * factor;
+            if sx < 0.0 || sy < 0.0 {
+                return None;
+            }
+            let (sx, sy) = (sx as usize, sy as usize);
+            if sx >= upscaled_width || sy >= height << passes {
+                return None;
+            }
+            upscaled[sx + sy * upscaled_width]
+        })
+        .collect();
+
+    Clipboard {
+        x: clipboard.x + (width as isize - new_width as isize) / 2,
+        y: clipboard.y + (height as isize - new_height as isize) / 2,
+        width: new_width,
+        height: new_height,
+        pixels,
+    }
+}
+
+/// Doubles the size of an image. Each pixel becomes four, which take the color of a
+/// neighbour where two of its neighbours meet at that corner, so diagonal lines stay
+/// lines instead of becoming staircases.
+fn scale2x(pixels: &[Option<Color32>], width: usize, height: usize) -> Pixels {
+    let mut scaled = vec![None; width * height * 4];
+    let at = |x: usize, y: usize| pixels[x + y * width];
+
+    for y in 0..height {
+        for x in 0..width {
+            let p = at(x, y);
+            // Neighbours above, right, left and below, the pixel itself at the edges
+            let a = if y > 0 { at(x, y - 1) } else { p };
+            let b = if x + 1 < width { at(x + 1, y) } else { p };
+            let c = if x > 0 { at(x - 1, y) } else { p };
+            let d = if y + 1 < height { at(x, y + 1) } else { p };
+
+            let (mut e0, mut e1, mut e2, mut e3) = (p, p, p, p);
+            if c == a && c != d && a != b {
+                e0 = a;
+            }
+            if a == b && a != c && b != d {
+                e1 = b;
+            }
+            if d == c && d != b && c != a {
+                e2 = c;
+            }
+            if b == d && b != a && d != c {
+                e3 = d;
+            }
+
+            let (sx, sy, sw) = (x * 2, y * 2, width * 2);
+            scaled[sx + sy * sw] = e0;
+            scaled[sx + 1 + sy * sw] = e1;
+            scaled[sx + (sy + 1) * sw] = e2;
+            scaled[sx + 1 + (sy + 1) * sw] = e3;
+        }
+    }
+    scaled
+}
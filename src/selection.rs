@@ -0,0 +1,322 @@
+use egui::{Color32, Pos2};
+
+/// A line between two pixel corners, `(x, y)` being the top left corner of pixel `(x, y)`.
+pub type Edge = ((usize, usize), (usize, usize));
+
+/// How a new selection is combined with the one there already is.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum SelectionMode {
+    #[default]
+    Replace,
+    Add,
+    Subtract,
+    Intersect,
+}
+
+impl SelectionMode {
+    pub const ALL: [SelectionMode; 4] = [
+        SelectionMode::Replace,
+        SelectionMode::Add,
+        SelectionMode::Subtract,
+        SelectionMode::Intersect,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SelectionMode::Replace => "Replace",
+            SelectionMode::Add => "Add",
+            SelectionMode::Subtract => "Subtract",
+            SelectionMode::Intersect => "Intersect",
+        }
+    }
+}
+
+/// The pixels of the canvas that tools are allowed to change, along with the outline
+/// drawn around them.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Selection {
+    width: usize,
+    height: usize,
+    mask: Vec<bool>,
+    /// Smallest rectangle `(x1, y1, x2, y2)` holding every selected pixel.
+    bounds: Option<(usize, usize, usize, usize)>,
+    /// Edges between selected and unselected pixels, as lines between pixel corners.
+    outline: Vec<Edge>,
+}
+
+impl Selection {
+    /// Selection of the pixels set in a `width * height` mask.
+    pub fn from_mask(width: usize, height: usize, mask: Vec<bool>) -> Self {
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (idx, _) in mask.iter().enumerate().filter(|(_, &m)| m) {
+            let (x, y) = (idx % width, idx / width);
+            bounds = Some(match bounds {
+                Some((x1, y1, x2, y2)) => (x1.min(x), y1.min(y), x2.max(x), y2.max(y)),
+                None => (x, y, x, y),
+            });
+        }
+
+        let mut selection = Self {
+            width,
+            height,
+            mask,
+            bounds,
+            outline: Vec::new(),
+        };
+        selection.outline = selection.find_outline();
+        selection
+    }
+
+    /// Selection of the rectangle between two corners, both included.
+    pub fn rect(width: usize, height: usize, start: (usize, usize), end: (usize, usize)) -> Self {
+        let (x1, x2) = (start.0.min(end.0), start.0.max(end.0).min(width - 1));
+        let (y1, y2) = (start.1.min(end.1), start.1.max(end.1).min(height - 1));
+        let mut mask = vec![false; width * height];
+        for y in y1..=y2 {
+            mask[(x1 + y * width)..=(x2 + y * width)].fill(true);
+        }
+        Self::from_mask(width, height, mask)
+    }
+
+    pub fn all(width: usize, height: usize) -> Self {
+        Self::from_mask(width, height, vec![true; width * height])
+    }
+
+    /// Selection of the pixels whose centers lie inside a polygon, given in pixel
+    /// coordinates. Self-intersecting polygons use the even-odd rule.
+    pub fn polygon(width: usize, height: usize, points: &[Pos2]) -> Self {
+        let mut mask = vec![false; width * height];
+        let mut crossings = Vec::new();
+        for y in 0..height {
+            // Find where the edges cross the middle of the row, then fill between
+            // every other pair of crossings
+            let center_y = y as f32 + 0.5;
+            crossings.clear();
+            for (i, a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+                if (a.y <= center_y) != (b.y <= center_y) {
+                    crossings.push(a.x + (center_y - a.y) / (b.y - a.y) * (b.x - a.x));
+                }
+            }
+            crossings.sort_by(f32::total_cmp);
+
+            for pair in crossings.chunks_exact(2) {
+                // Pixels whose centers lie between the crossings
+                let x1 = (pair[0] - 0.5).ceil().max(0.0) as usize;
+                let x2 = ((pair[1] - 0.5).ceil().max(0.0) as usize).min(width);
+                for x in x1..x2 {
+                    mask[x + y * width] = true;
+                }
+            }
+        }
+        Self::from_mask(width, height, mask)
+    }
+
+    /// Combines a new selection with this one.
+    pub fn combine(&self, other: &Selection, mode: SelectionMode) -> Self {
+        let mask = self
+            .mask
+            .iter()
+            .zip(&other.mask)
+            .map(|(&a, &b)| match mode {
+                SelectionMode::Replace => b,
+                SelectionMode::Add => a || b,
+                SelectionMode::Subtract => a && !b,
+                SelectionMode::Intersect => a && b,
+            })
+            .collect();
+        Self::from_mask(self.width, self.height, mask)
+    }
+
+    /// Every pixel that is not selected.
+    pub fn inverted(&self) -> Self {
+        let mask = self.mask.iter().map(|&m| !m).collect();
+        Self::from_mask(self.width, self.height, mask)
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height && self.mask[x + y * self.width]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bounds.is_none()
+    }
+
+    pub fn get_size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn get_bounds(&self) -> Option<(usize, usize, usize, usize)> {
+        self.bounds
+    }
+
+    pub fn get_mask(&self) -> &[bool] {
+        &self.mask
+    }
+
+    pub fn get_outline(&self) -> &[Edge] {
+        &self.outline
+    }
+
+    /// The same selection moved by `(dx, dy)`, losing whatever ends up off the canvas.
+    pub fn translated(&self, dx: isize, dy: isize) -> Self {
+        let mut mask = vec![false; self.width * self.height];
+        if let Some((x1, y1, x2, y2)) = self.bounds {
+            for y in y1..=y2 {
+                for x in x1..=x2 {
+                    let (nx, ny) = (x as isize + dx, y as isize + dy);
+                    if !self.contains(x, y)
+                        || nx < 0
+                        || ny < 0
+                        || nx as usize >= self.width
+                        || ny as usize >= self.height
+                    {
+                        continue;
+                    }
+                    mask[nx as usize + ny as usize * self.width] = true;
+                }
+            }
+        }
+        Self::from_mask(self.width, self.height, mask)
+    }
+
+    /// Walks the rows and columns around the selection, joining neighbouring edges
+    /// into one line so the dashes of the outline run on between pixels.
+    fn find_outline(&self) -> Vec<Edge> {
+        let Some((x1, y1, x2, y2)) = self.bounds else {
+            return Vec::new();
+        };
+        let selected =
+            |x: isize, y: isize| x >= 0 && y >= 0 && self.contains(x as usize, y as usize);
+
+        let mut outline = Vec::new();
+        for y in y1..=(y2 + 1) {
+            let mut start = None;
+            for x in x1..=(x2 + 1) {
+                let (xi, yi) = (x as isize, y as isize);
+                let edge = x <= x2 && selected(xi, yi - 1) != selected(xi, yi);
+                match (edge, start) {
+                    (true, None) => start = Some(x),
+                    (false, Some(sx)) => {
+                        outline.push(((sx, y), (x, y)));
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for x in x1..=(x2 + 1) {
+            let mut start = None;
+            for y in y1..=(y2 + 1) {
+                let (xi, yi) = (x as isize, y as isize);
+                let edge = y <= y2 && selected(xi - 1, yi) != selected(xi, yi);
+                match (edge, start) {
+                    (true, None) => start = Some(y),
+                    (false, Some(sy)) => {
+                        outline.push(((x, sy), (x, y)));
+                        start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        outline
+    }
+}
+
+/// Flips and quarter turns, which move pixels around without changing any of them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Transform {
+    FlipHorizontal,
+    FlipVertical,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Transform {
+    pub const ALL: [Transform; 5] = [
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transform::FlipHorizontal => "Flip horizontally",
+            Transform::FlipVertical => "Flip vertically",
+            Transform::Rotate90 => "Rotate 90° clockwise",
+            Transform::Rotate180 => "Rotate 180°",
+            Transform::Rotate270 => "Rotate 90° counterclockwise",
+        }
+    }
+}
+
+/// Pixels copied out of a selection. They remember where they were copied from, so
+/// pasting puts them back in the same place, on whichever layer is active. Transformed
+/// pixels can stick out of the canvas, so the position may be negative.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Clipboard {
+    pub x: isize,
+    pub y: isize,
+    pub width: usize,
+    pub height: usize,
+    /// `width * height` pixels, `None` where nothing was selected.
+    pub pixels: Vec<Option<Color32>>,
+}
+
+impl Clipboard {
+    /// The pixels flipped or turned about the center of their rectangle.
+    pub fn transformed(&self, transform: Transform) -> Self {
+        let (width, height) = (self.width, self.height);
+        let (new_width, new_height) = match transform {
+            Transform::Rotate90 | Transform::Rotate270 => (height, width),
+            _ => (width, height),
+        };
+        // Where each pixel of the result comes from
+        let source = |x: usize, y: usize| match transform {
+            Transform::FlipHorizontal => (width - 1 - x, y),
+            Transform::FlipVertical => (x, height - 1 - y),
+            Transform::Rotate90 => (y, height - 1 - x),
+            Transform::Rotate180 => (width - 1 - x, height - 1 - y),
+            Transform::Rotate270 => (width - 1 - y, x),
+        };
+        let pixels = (0..new_width * new_height)
+            .map(|idx| {
+                let (sx, sy) = source(idx % new_width, idx / new_width);
+                self.pixels[sx + sy * width]
+            })
+            .collect();
+
+        Self {
+            // Truncating the halves the same way both ways lets four quarter turns
+            // end up where they started
+            x: self.x + (width as isize - new_width as isize) / 2,
+            y: self.y + (height as isize - new_height as isize) / 2,
+            width: new_width,
+            height: new_height,
+            pixels,
+        }
+    }
+
+    /// The pixels stretched over a new rectangle, nearest-neighbour.
+    pub fn scaled(&self, x: isize, y: isize, width: usize, height: usize) -> Self {
+        let pixels = (0..width * height)
+            .map(|idx| {
+                // Sample at the middle of each new pixel
+                let sx = (2 * (idx % width) + 1) * self.width / (2 * width);
+                let sy = (2 * (idx / width) + 1) * self.height / (2 * height);
+                self.pixels[sx + sy * self.width]
+            })
+            .collect();
+        Self {
+            x,
+            y,
+            width,
+            height,
+            pixels,
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use crate::export::{check_export_size, content_bounds, MAX_EXPORT_SCALE};
+use crate::model::Canvas;
+use egui::Color32;
+use image::{ImageBuffer, ImageFormat, ImageResult, Rgba};
+use serde::{Serialize, Serializer};
+use std::io::Write;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum SheetLayout {
+    #[default]
+    Grid,
+    Row,
+    Column,
+    /// Sprites are trimmed to their content and packed as tightly as possible.
+    Packed,
+}
+
+impl SheetLayout {
+    pub const ALL: [SheetLayout; 4] = [
+        SheetLayout::Grid,
+        SheetLayout::Row,
+        SheetLayout::Column,
+        SheetLayout::Packed,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SheetLayout::Grid => "Grid",
+            SheetLayout::Row => "Row",
+            SheetLayout::Column => "Column",
+            SheetLayout::Packed => "Packed",
+        }
+    }
+}
+
+/// The two JSON shapes used by Aseprite and TexturePacker: `frames` as an object keyed
+/// by sprite name, or as an array of sprites that each carry a `filename`.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum AtlasFormat {
+    #[default]
+    Hash,
+    Array,
+}
+
+impl AtlasFormat {
+    pub const ALL: [AtlasFormat; 2] = [AtlasFormat::Hash, AtlasFormat::Array];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AtlasFormat::Hash => "JSON Hash",
+            AtlasFormat::Array => "JSON Array",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SpriteSheetOptions {
+    pub layout: SheetLayout,
+    pub atlas_format: AtlasFormat,
+    pub scale: u32,
+    /// Empty pixels around the edge of the sheet.
+    pub padding: u32,
+    /// Empty pixels between neighbouring sprites.
+    pub spacing: u32,
+    /// Export every layer of every frame as its own sprite.
+    pub split_layers: bool,
+    pub include_hidden: bool,
+}
+
+impl Default for SpriteSheetOptions {
+    fn default() -> Self {
+        Self {
+            layout: SheetLayout::default(),
+            atlas_format: AtlasFormat::default(),
+            scale: 1,
+            padding: 0,
+            spacing: 0,
+            split_layers: false,
+            include_hidden: false,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct Size {
+    pub w: u32,
+    pub h: u32,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AtlasFrame {
+    #[serde(skip)]
+    pub filename: String,
+    /// Where the sprite is on the sheet.
+    pub frame: Rect,
+    pub rotated: bool,
+    pub trimmed: bool,
+    /// Where the trimmed sprite sits inside the untrimmed one.
+    pub sprite_source_size: Rect,
+    pub source_size: Size,
+    pub duration: u32,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct FrameTag {
+    pub name: String,
+    pub from: usize,
+    pub to: usize,
+    pub direction: &'static str,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AtlasLayer {
+    pub name: String,
+    pub opacity: u8,
+    pub blend_mode: &'static str,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AtlasMeta {
+    pub app: &'static str,
+    pub version: &'static str,
+    pub image: String,
+    pub format: &'static str,
+    pub size: Size,
+    pub scale: String,
+    pub frame_tags: Vec<FrameTag>,
+    pub layers: Vec<AtlasLayer>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Atlas {
+    pub frames: Vec<AtlasFrame>,
+    pub meta: AtlasMeta,
+}
+
+pub struct SpriteSheet {
+    pub image: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    pub atlas: Atlas,
+}
+
+/// A frame, or a single layer of a frame, before it is placed on the sheet.
+struct Sprite {
+    name: String,
+    frame_idx: usize,
+    pixels: Vec<Color32>,
+    /// Part of the sprite that ends up on the sheet, in canvas pixels.
+    trim: Rect,
+}
+
+/// Packs the frames of the canvas into one image. `name` is used for the sprite names
+/// and the frame tag, `image_name` is the file name the atlas points at.
+pub fn build(
+    canvas: &Canvas,
+    options: &SpriteSheetOptions,
+    name: &str,
+    image_name: &str,
+) -> ImageResult<SpriteSheet> {
+    let width = canvas.get_width();
+    let height = canvas.get_height();
+    let scale = options.scale.clamp(1, MAX_EXPORT_SCALE);
+    let frames = canvas.get_num_frames();
+
+    let layers: Vec<usize> = (0..canvas.get_num_layers())
+        .filter(|&i| options.include_hidden || canvas.is_layer_shown(i))
+        .collect();
+
+    let mut sprites = Vec::new();
+    if options.split_layers {
+        for &layer_idx in &layers {
+            for frame_idx in 0..frames {
+                sprites.push(Sprite {
+                    name: format!(
+                        "{} ({}) {}",
+                        name,
+                        canvas.get_layer_name(layer_idx),
+                        frame_idx
+                    ),
+                    frame_idx,
+                    pixels: canvas.get_cel(layer_idx, frame_idx).to_vec(),
+                    trim: Rect {
+                        x: 0,
+                        y: 0,
+                        w: width as u32,
+                        h: height as u32,
+                    },
+                });
+            }
+        }
+    } else {
+        for frame_idx in 0..frames {
+            sprites.push(Sprite {
+                name: format!("{} {}", name, frame_idx),
+                frame_idx,
+                pixels: canvas.flatten_frame(frame_idx, options.include_hidden),
+                trim: Rect {
+                    x: 0,
+                    y: 0,
+                    w: width as u32,
+                    h: height as u32,
+                },
+            });
+        }
+    }
+
+    if options.layout == SheetLayout::Packed {
+        for sprite in &mut sprites {
+            // Empty sprites keep a single pixel so engines never see a zero sized rect
+            let (x1, y1, x2, y2) =
+                content_bounds(&sprite.pixels, width, height).unwrap_or_default();
+            sprite.trim = Rect {
+                x: x1 as u32,
+                y: y1 as u32,
+                w: (x2 - x1 + 1) as u32,
+                h: (y2 - y1 + 1) as u32,
+            };
+        }
+    }
+
+    let sizes: Vec<Size> = sprites
+        .iter()
+        .map(|s| Size {
+            w: s.trim.w * scale,
+            h: s.trim.h * scale,
+        })
+        .collect();
+    // The sprites alone bound the placement math, the sheet itself is checked below
+    check_export_size(
+        sizes.iter().map(|s| s.w as u64 * s.h as u64).sum(),
+        ImageFormat::Png,
+    )?;
+    let (positions, sheet_size) = match options.layout {
+        SheetLayout::Grid => {
+            let columns = (sprites.len() as f64).sqrt().ceil() as usize;
+            place_in_grid(&sizes, columns, options)
+        }
+        SheetLayout::Row => place_in_grid(&sizes, sprites.len(), options),
+        SheetLayout::Column => place_in_grid(&sizes, 1, options),
+        SheetLayout::Packed => place_packed(&sizes, options),
+    };
+
+    check_export_size(sheet_size.w as u64 * sheet_size.h as u64, ImageFormat::Png)?;
+    let mut image = ImageBuffer::new(sheet_size.w, sheet_size.h);
+    for (sprite, &(x, y)) in sprites.iter().zip(&positions) {
+        for dy in 0..sprite.trim.h * scale {
+            for dx in 0..sprite.trim.w * scale {
+                let px = (sprite.trim.x + dx / scale) as usize;
+                let py = (sprite.trim.y + dy / scale) as usize;
+                let color = sprite.pixels[px + py * width];
+                image.put_pixel(x + dx, y + dy, Rgba(color.to_srgba_unmultiplied()));
+            }
+        }
+    }
+
+    let atlas_frames = sprites
+        .iter()
+        .zip(&positions)
+        .zip(&sizes)
+        .map(|((sprite, &(x, y)), size)| AtlasFrame {
+            filename: sprite.name.clone(),
+            frame: Rect {
+                x,
+                y,
+                w: size.w,
+                h: size.h,
+            },
+            rotated: false,
+            trimmed: sprite.trim.w as usize != width || sprite.trim.h as usize != height,
+            sprite_source_size: Rect {
+                x: sprite.trim.x * scale,
+                y: sprite.trim.y * scale,
+                w: size.w,
+                h: size.h,
+            },
+            source_size: Size {
+                w: width as u32 * scale,
+                h: height as u32 * scale,
+            },
+            duration: canvas.get_frame_duration(sprite.frame_idx),
+        })
+        .collect();
+
+    let meta = AtlasMeta {
+        app: "Ruxel",
+        version: env!("CARGO_PKG_VERSION"),
+        image: image_name.to_string(),
+        format: "RGBA8888",
+        size: sheet_size,
+        scale: scale.to_string(),
+        frame_tags: vec![FrameTag {
+            name: name.to_string(),
+            from: 0,
+            to: frames - 1,
+            direction: "forward",
+        }],
+        layers: layers
+            .iter()
+            .map(|&i| AtlasLayer {
+                name: canvas.get_layer_name(i).clone(),
+                opacity: 255,
+                blend_mode: "normal",
+            })
+            .collect(),
+    };
+
+    Ok(SpriteSheet {
+        image,
+        atlas: Atlas {
+            frames: atlas_frames,
+            meta,
+        },
+    })
+}
+
+/// Lays the sprites out left to right, wrapping after `columns` of them. Every cell is
+/// as big as the largest sprite.
+fn place_in_grid(
+    sizes: &[Size],
+    columns: usize,
+    options: &SpriteSheetOptions,
+) -> (Vec<(u32, u32)>, Size) {
+    let columns = columns.max(1);
+    let rows = sizes.len().div_ceil(columns).max(1);
+    let cell_w = sizes.iter().map(|s| s.w).max().unwrap_or(0);
+    let cell_h = sizes.iter().map(|s| s.h).max().unwrap_or(0);
+
+    let positions = (0..sizes.len())
+        .map(|i| {
+            let (col, row) = ((i % columns) as u32, (i / columns) as u32);
+            (
+                options.padding + col * (cell_w + options.spacing),
+                options.padding + row * (cell_h + options.spacing),
+            )
+        })
+        .collect();
+    let sheet_size = Size {
+        w: 2 * options.padding + columns as u32 * (cell_w + options.spacing) - options.spacing,
+        h: 2 * options.padding + rows as u32 * (cell_h + options.spacing) - options.spacing,
+    };
+    (positions, sheet_size)
+}
+
+/// Shelf packing: the tallest sprites go first, filling rows of a roughly square sheet.
+fn place_packed(sizes: &[Size], options: &SpriteSheetOptions) -> (Vec<(u32, u32)>, Size) {
+    let area: u64 = sizes
+        .iter()
+        .map(|s| (s.w + options.spacing) as u64 * (s.h + options.spacing) as u64)
+        .sum();
+    let widest = sizes.iter().map(|s| s.w).max().unwrap_or(0);
+    let max_width = widest.max((area as f64).sqrt().ceil() as u32);
+
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].h));
+
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut x, mut y) = (0, 0);
+    let mut shelf_height = 0;
+    let (mut used_w, mut used_h) = (0, 0);
+    for i in order {
+        let size = sizes[i];
+        if x > 0 && x + size.w > max_width {
+            x = 0;
+            y += shelf_height + options.spacing;
+            shelf_height = 0;
+        }
+        positions[i] = (options.padding + x, options.padding + y);
+        used_w = used_w.max(x + size.w);
+        used_h = used_h.max(y + size.h);
+        shelf_height = shelf_height.max(size.h);
+        x += size.w + options.spacing;
+    }
+
+    let sheet_size = Size {
+        w: 2 * options.padding + used_w,
+        h: 2 * options.padding + used_h,
+    };
+    (positions, sheet_size)
+}
+
+/// Writes the atlas as pretty printed JSON in the chosen format.
+pub fn write_atlas<W: Write>(
+    writer: W,
+    atlas: &Atlas,
+    format: AtlasFormat,
+) -> serde_json::Result<()> {
+    #[derive(Serialize)]
+    struct Document<'a, F: Serialize> {
+        frames: F,
+        meta: &'a AtlasMeta,
+    }
+
+    #[derive(Serialize)]
+    struct ArrayFrame<'a> {
+        filename: &'a str,
+        #[serde(flatten)]
+        frame: &'a AtlasFrame,
+    }
+
+    /// Serializes as an object keyed by file name, keeping the frame order.
+    struct HashFrames<'a>(&'a [AtlasFrame]);
+
+    impl Serialize for HashFrames<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(self.0.iter().map(|f| (&f.filename, f)))
+        }
+    }
+
+    match format {
+        AtlasFormat::Hash => serde_json::to_writer_pretty(
+            writer,
+            &Document {
+                frames: HashFrames(&atlas.frames),
+                meta: &atlas.meta,
+            },
+        ),
+        AtlasFormat::Array => serde_json::to_writer_pretty(
+            writer,
+            &Document {
+                frames: atlas
+                    .frames
+                    .iter()
+                    .map(|f| ArrayFrame {
+                        filename: &f.filename,
+                        frame: f,
+                    })
+                    .collect::<Vec<_>>(),
+                meta: &atlas.meta,
+            },
+        ),
+    }
+}
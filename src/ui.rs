@@ -1,16 +1,38 @@
+mod export_modal;
+mod history_panel;
 mod new_file_modal;
+mod notifications;
 mod palette;
+mod rotate_modal;
+mod sprite_sheet_modal;
 
 use crate::change_manager::ChangeManager;
-use crate::file_interactions::FileInteractions;
-use crate::model::Canvas;
+use crate::export::{ExportFormat, MAX_EXPORT_SCALE};
+use crate::file_interactions::{FileInteractions, OpenedFile};
+use crate::model::{
+    Canvas, FillOptions, ShapeMode, ShapeStyle, SymmetryMode, MARCHING_ANTS_STEP, MAX_CANVAS_SIZE,
+};
+use crate::selection::{Clipboard, SelectionMode, Transform};
+use crate::sprite_sheet::{AtlasFormat, SheetLayout};
 use egui::*;
+use export_modal::*;
+use history_panel::*;
 use new_file_modal::*;
+use notifications::*;
 use palette::*;
+use rotate_modal::*;
+use sprite_sheet_modal::*;
 
 const CAT_FLAVOR: catppuccin::Flavor = catppuccin::PALETTE.frappe;
 const UNDO_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::CTRL, Key::Z);
 const REDO_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::CTRL, Key::Y);
+const SELECT_ALL_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::CTRL, Key::A);
+const DESELECT_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::CTRL, Key::D);
+const INVERT_SELECTION_SHORTCUT: KeyboardShortcut =
+    KeyboardShortcut::new(Modifiers::CTRL.plus(Modifiers::SHIFT), Key::I);
+/// How close in screen points a click has to be to the first point of a polygon lasso
+/// to close it.
+const LASSO_CLOSE_DISTANCE: f32 = 8.0;
 
 #[derive(PartialEq, Debug, Default)]
 enum Tool {
@@ -19,7 +41,13 @@ enum Tool {
     Erase,
     Fill,
     Rectangle(Option<Pos2>),
+    Ellipse(Option<Pos2>),
+    Line(Option<Pos2>),
     Eyedrop,
+    Select(Option<Pos2>),
+    Lasso,
+    MagicWand,
+    Scale,
 }
 
 // impl Default for Tool {
@@ -35,9 +63,38 @@ pub struct Ruxel {
     canvas: Option<Canvas>,
     active_tool: Tool,
     tool_size: usize,
+    /// Whether one pixel draw strokes leave out their L-shaped corners.
+    pixel_perfect: bool,
+    fill_options: FillOptions,
+    rect_mode: ShapeMode,
+    ellipse_mode: ShapeMode,
+    corner_radius: usize,
+    /// Color of the inside of shapes drawn with both an outline and a fill.
+    fill_color: eframe::egui::Color32,
+    wand_options: FillOptions,
+    selection_mode: SelectionMode,
+    polygon_lasso: bool,
+    /// Points of the lasso being drawn, in pixels.
+    lasso_points: Vec<Pos2>,
+    integer_scale: bool,
+    /// Scale handle being dragged, with the bounds it would scale to.
+    scale_drag: Option<(usize, (isize, isize, isize, isize))>,
+    /// Symmetry axis handle being dragged.
+    symmetry_drag: Option<usize>,
+    /// Time at which the current frame started showing, while the animation plays.
+    playback: Option<f64>,
     file_interactions: FileInteractions,
     new_file_modal: NewFileModal,
+    export_modal: ExportModal,
+    sprite_sheet_modal: SpriteSheetModal,
+    rotate_modal: RotateModal,
+    history_panel: HistoryPanel,
+    notifications: Notifications,
     change_manager: Option<ChangeManager>,
+    clipboard: Option<Clipboard>,
+    /// Whether the press of the paste key that is down came through, as a key or as a
+    /// text paste.
+    paste_key_seen: bool,
 }
 
 impl Default for Ruxel {
@@ -49,9 +106,30 @@ impl Default for Ruxel {
             canvas: None,
             active_tool: Tool::default(),
             tool_size: 1,
+            pixel_perfect: false,
+            fill_options: FillOptions::default(),
+            rect_mode: ShapeMode::Filled,
+            ellipse_mode: ShapeMode::Outline,
+            corner_radius: 0,
+            fill_color: eframe::egui::Color32::from_rgb(0, 0, 0),
+            wand_options: FillOptions::default(),
+            selection_mode: SelectionMode::default(),
+            polygon_lasso: false,
+            lasso_points: Vec::new(),
+            integer_scale: false,
+            scale_drag: None,
+            symmetry_drag: None,
+            playback: None,
             file_interactions: FileInteractions::new(),
             new_file_modal: NewFileModal::default(),
+            export_modal: ExportModal::default(),
+            sprite_sheet_modal: SpriteSheetModal::default(),
+            rotate_modal: RotateModal::default(),
+            history_panel: HistoryPanel::default(),
+            notifications: Notifications::default(),
             change_manager: None,
+            clipboard: None,
+            paste_key_seen: false,
         }
     }
 }
@@ -71,92 +149,257 @@ impl Ruxel {
                 ui.horizontal_centered(|ui| {
                     egui::menu::bar(ui, |ui| {
                         let _file_response = ui.menu_button("File", |ui| {
-                            if ui.button("Save").clicked() {
-                                self.file_interactions.show_save_dialog = true;
+                            if ui.button("Export").clicked() {
+                                if let Some(c) = &self.canvas {
+                                    self.export_modal.activate(*c.get_export_options());
+                                }
+                                ui.close_menu();
                             }
 
-                            if self.file_interactions.show_save_dialog {
-                                let rgba_buff =
-                                    Canvas::get_rgba_buffer(self.canvas.as_ref().unwrap());
-
-                                FileInteractions::save_file(&mut self.file_interactions, rgba_buff);
+                            if ui.button("Export Sprite Sheet").clicked() {
+                                if self.canvas.is_some() {
+                                    self.sprite_sheet_modal.activate();
+                                }
+                                ui.close_menu();
                             }
 
                             if ui.button("New").clicked() {
                                 self.new_file_modal.activate();
                             }
 
+                            if ui.button("Open").clicked() {
+                                match self.file_interactions.open_file() {
+                                    Ok(Some(opened)) => {
+                                        let canvas = match opened {
+                                            OpenedFile::Image(rgba_buff) => {
+                                                Canvas::from_rgba_buffer(&rgba_buff)
+                                            }
+                                            OpenedFile::Project(project) => {
+                                                let (canvas, palette) = project.into_canvas();
+                                                self.color_pallete = palette;
+                                                canvas
+                                            }
+                                        };
+                                        self.change_manager = Some(ChangeManager::new());
+                                        self.canvas = Some(canvas);
+                                    }
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        self.notifications.error(format!("Could not open {}", e))
+                                    }
+                                }
+                                ui.close_menu();
+                            }
+
+                            if ui.button("Save Project").clicked() {
+                                if let Some(c) = &self.canvas {
+                                    match self
+                                        .file_interactions
+                                        .save_project(c, &self.color_pallete)
+                                    {
+                                        Ok(Some(path)) => self
+                                            .notifications
+                                            .info(format!("Saved {}", path.display())),
+                                        Ok(None) => {}
+                                        Err(e) => self
+                                            .notifications
+                                            .error(format!("Could not save {}", e)),
+                                    }
+                                }
+                                ui.close_menu();
+                            }
+
                             if ui.button("Close").clicked() {
                                 std::process::exit(0);
                             }
                         });
 
                         let _edit_response = ui.menu_button("Edit", |ui| {
-                            let undo_response = ui.button("Undo");
-                            if undo_response.clicked() {
-                                match &mut self.canvas {
-                                    Some(c) => {
-                                        if let Some(cm) = &mut self.change_manager {
-                                            if !cm.is_undo_empty() {
-                                                let previous_state = cm.undo();
+                            let (undo_text, redo_text) = match &self.change_manager {
+                                Some(cm) => (
+                                    cm.undo_name().map(|name| format!("Undo {}", name)),
+                                    cm.redo_name().map(|name| format!("Redo {}", name)),
+                                ),
+                                None => (None, None),
+                            };
 
-                                                c.load_state(previous_state);
-                                            }
+                            let undo_response =
+                                ui.button(undo_text.unwrap_or_else(|| String::from("Undo")));
+                            if undo_response.clicked() {
+                                if let Some(c) = &mut self.canvas {
+                                    if let Some(cm) = &mut self.change_manager {
+                                        if !cm.is_undo_empty() {
+                                            cm.undo(c);
                                         }
                                     }
-                                    None => {}
                                 }
                             }
                             undo_response.on_hover_text("Ctrl+Z");
 
-                            let redo_response = ui.button("Redo");
+                            let redo_response =
+                                ui.button(redo_text.unwrap_or_else(|| String::from("Redo")));
                             if redo_response.clicked() {
-                                match &mut self.canvas {
-                                    Some(c) => {
-                                        if let Some(cm) = &mut self.change_manager {
-                                            if !cm.is_redo_empty() {
-                                                let next_state = cm.redo();
-
-                                                c.load_state(next_state);
-                                            }
+                                if let Some(c) = &mut self.canvas {
+                                    if let Some(cm) = &mut self.change_manager {
+                                        if !cm.is_redo_empty() {
+                                            cm.redo(c);
                                         }
                                     }
-                                    None => {}
                                 }
                             }
                             redo_response.on_hover_text("Ctrl+Y");
+
+                            ui.separator();
+
+                            if ui.button("Cut").on_hover_text("Ctrl+X").clicked() {
+                                self.cut();
+                                ui.close_menu();
+                            }
+                            if ui.button("Copy").on_hover_text("Ctrl+C").clicked() {
+                                self.copy();
+                                ui.close_menu();
+                            }
+                            if ui.button("Paste").on_hover_text("Ctrl+V").clicked() {
+                                self.paste();
+                                ui.close_menu();
+                            }
+                            if ui.button("Delete").on_hover_text("Del").clicked() {
+                                self.delete_selection();
+                                ui.close_menu();
+                            }
+
+                            ui.separator();
+
+                            if ui.button("Select All").on_hover_text("Ctrl+A").clicked() {
+                                self.select_all();
+                                ui.close_menu();
+                            }
+                            if ui.button("Deselect").on_hover_text("Ctrl+D").clicked() {
+                                self.deselect();
+                                ui.close_menu();
+                            }
+                            let invert_response = ui.button("Invert Selection");
+                            if invert_response.clicked() {
+                                self.invert_selection();
+                                ui.close_menu();
+                            }
+                            invert_response.on_hover_text("Ctrl+Shift+I");
                         });
 
+                        let _transform_response = ui.menu_button("Transform", |ui| {
+                            for transform in Transform::ALL {
+                                if ui.button(transform.name()).clicked() {
+                                    if let (Some(c), Some(cm)) =
+                                        (&mut self.canvas, &mut self.change_manager)
+                                    {
+                                        c.transform_selection(transform);
+                                        cm.push_change(c, transform.name());
+                                    }
+                                    ui.close_menu();
+                                }
+                            }
+
+                            ui.separator();
+
+                            if ui.button("Rotate...").clicked() {
+                                if self.canvas.is_some() {
+                                    self.rotate_modal.activate();
+                                }
+                                ui.close_menu();
+                            }
+                        });
+
+                        let _view_response = ui.menu_button("View", |ui| {
+                            ui.checkbox(&mut self.history_panel.open, "History");
+                        });
+
+                        // Text fields handle their own copy and paste
+                        let canvas_focused = ui.ctx().memory(|m| m.focused().is_none());
+                        let mut clipboard_event = None;
+                        let mut paste = false;
+                        let mut selection_shortcut = None;
+
                         // check for Ctrl+Z and Ctrl+Y
                         ui.input_mut(|i| {
-                            if i.consume_shortcut(&UNDO_SHORTCUT) {
-                                match &mut self.canvas {
-                                    Some(c) => {
-                                        if let Some(cm) = &mut self.change_manager {
-                                            if !cm.is_undo_empty() {
-                                                let previous_state = cm.undo();
+                            if canvas_focused {
+                                selection_shortcut = [
+                                    SELECT_ALL_SHORTCUT,
+                                    DESELECT_SHORTCUT,
+                                    INVERT_SELECTION_SHORTCUT,
+                                ]
+                                .into_iter()
+                                .find(|shortcut| i.consume_shortcut(shortcut));
+                            }
+                            // Pressing Ctrl+V only comes through as a text paste, and
+                            // only when the system clipboard holds text. Without text
+                            // just the key being let go comes through, so letting go of
+                            // a key that was never seen pressed pastes too.
+                            for event in &i.events {
+                                match event {
+                                    Event::Paste(_) => {
+                                        paste = true;
+                                        self.paste_key_seen = true;
+                                    }
+                                    Event::Key {
+                                        key: Key::V | Key::Paste,
+                                        pressed,
+                                        ..
+                                    } => {
+                                        paste |= !pressed && !self.paste_key_seen;
+                                        self.paste_key_seen = *pressed;
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            paste &= canvas_focused;
+                            if canvas_focused {
+                                clipboard_event = i.events.iter().find_map(|event| match event {
+                                    Event::Cut | Event::Copy => Some(event.clone()),
+                                    Event::Key {
+                                        key: Key::Delete,
+                                        pressed: true,
+                                        ..
+                                    } => Some(event.clone()),
+                                    _ => None,
+                                });
+                            }
 
-                                                c.load_state(previous_state);
-                                            }
+                            if i.consume_shortcut(&UNDO_SHORTCUT) {
+                                if let Some(c) = &mut self.canvas {
+                                    if let Some(cm) = &mut self.change_manager {
+                                        if !cm.is_undo_empty() {
+                                            cm.undo(c);
                                         }
                                     }
-                                    None => {}
                                 }
                             } else if i.consume_shortcut(&REDO_SHORTCUT) {
-                                match &mut self.canvas {
-                                    Some(c) => {
-                                        if let Some(cm) = &mut self.change_manager {
-                                            if !cm.is_redo_empty() {
-                                                let next_state = cm.redo();
-
-                                                c.load_state(next_state);
-                                            }
+                                if let Some(c) = &mut self.canvas {
+                                    if let Some(cm) = &mut self.change_manager {
+                                        if !cm.is_redo_empty() {
+                                            cm.redo(c);
                                         }
                                     }
-                                    None => {}
                                 }
                             }
                         });
+
+                        match selection_shortcut {
+                            Some(SELECT_ALL_SHORTCUT) => self.select_all(),
+                            Some(DESELECT_SHORTCUT) => self.deselect(),
+                            Some(INVERT_SELECTION_SHORTCUT) => self.invert_selection(),
+                            _ => {}
+                        }
+                        match clipboard_event {
+                            Some(Event::Cut) => self.cut(),
+                            Some(Event::Copy) => self.copy(),
+                            Some(Event::Key {
+                                key: Key::Delete, ..
+                            }) => self.delete_selection(),
+                            _ => {}
+                        }
+                        if paste {
+                            self.paste();
+                        }
                     });
                 });
             });
@@ -197,13 +440,174 @@ impl Ruxel {
                             println!("Current tool {:?}", self.active_tool);
                         }
 
+                        let ellipse_response = ui.selectable_value(
+                            &mut self.active_tool,
+                            Tool::Ellipse(None),
+                            "Ellipse",
+                        );
+                        if ellipse_response.clicked() {
+                            self.active_tool = Tool::Ellipse(None);
+                        }
+
+                        let line_response =
+                            ui.selectable_value(&mut self.active_tool, Tool::Line(None), "Line");
+                        if line_response.clicked() {
+                            self.active_tool = Tool::Line(None);
+                        }
+
                         let eyedrop_response =
                             ui.selectable_value(&mut self.active_tool, Tool::Eyedrop, "Eyedrop");
                         if eyedrop_response.clicked() {
                             self.active_tool = Tool::Eyedrop;
                             println!("Current tool {:?}", self.active_tool);
                         }
+                        let select_response = ui.selectable_value(
+                            &mut self.active_tool,
+                            Tool::Select(None),
+                            "Select",
+                        );
+                        if select_response.clicked() {
+                            self.active_tool = Tool::Select(None);
+                        }
+                        let lasso_response =
+                            ui.selectable_value(&mut self.active_tool, Tool::Lasso, "Lasso");
+                        if lasso_response.clicked() {
+                            self.active_tool = Tool::Lasso;
+                        }
+                        let wand_response = ui.selectable_value(
+                            &mut self.active_tool,
+                            Tool::MagicWand,
+                            "Magic Wand",
+                        );
+                        if wand_response.clicked() {
+                            self.active_tool = Tool::MagicWand;
+                        }
+                        let scale_response =
+                            ui.selectable_value(&mut self.active_tool, Tool::Scale, "Scale");
+                        if scale_response.clicked() {
+                            self.active_tool = Tool::Scale;
+                        }
+                        if self.active_tool != Tool::Lasso {
+                            self.lasso_points.clear();
+                        }
                         ui.add(egui::Slider::new(&mut self.tool_size, 1..=50).suffix("px"));
+
+                        if self.active_tool == Tool::Draw {
+                            ui.separator();
+                            // Only a one pixel brush has corners to leave out
+                            ui.add_enabled(
+                                self.tool_size == 1,
+                                egui::Checkbox::new(&mut self.pixel_perfect, "Pixel perfect"),
+                            );
+                        }
+
+                        let shape_mode = match self.active_tool {
+                            Tool::Rectangle(_) => Some(&mut self.rect_mode),
+                            Tool::Ellipse(_) => Some(&mut self.ellipse_mode),
+                            _ => None,
+                        };
+                        if let Some(shape_mode) = shape_mode {
+                            ui.separator();
+                            egui::ComboBox::from_id_source("Shape Mode")
+                                .selected_text(shape_mode.name())
+                                .show_ui(ui, |ui| {
+                                    for mode in ShapeMode::ALL {
+                                        ui.selectable_value(shape_mode, mode, mode.name());
+                                    }
+                                });
+                            if *shape_mode == ShapeMode::OutlineAndFill {
+                                ui.label("Fill");
+                                ui.color_edit_button_srgba(&mut self.fill_color);
+                            }
+                        }
+                        if matches!(self.active_tool, Tool::Rectangle(_)) {
+                            ui.label("Corners");
+                            ui.add(
+                                egui::DragValue::new(&mut self.corner_radius)
+                                    .clamp_range(0..=64)
+                                    .suffix("px"),
+                            );
+                        }
+
+                        if self.active_tool == Tool::Fill {
+                            ui.separator();
+                            fill_options_ui(ui, &mut self.fill_options);
+                        }
+
+                        if matches!(
+                            self.active_tool,
+                            Tool::Select(_) | Tool::Lasso | Tool::MagicWand
+                        ) {
+                            ui.separator();
+                            egui::ComboBox::from_id_source("Selection Mode")
+                                .selected_text(self.selection_mode.name())
+                                .show_ui(ui, |ui| {
+                                    for mode in SelectionMode::ALL {
+                                        ui.selectable_value(
+                                            &mut self.selection_mode,
+                                            mode,
+                                            mode.name(),
+                                        );
+                                    }
+                                })
+                                .response
+                                .on_hover_text(
+                                    "Hold Shift to add, Alt to subtract, both to intersect",
+                                );
+                        }
+                        if self.active_tool == Tool::Lasso {
+                            ui.checkbox(&mut self.polygon_lasso, "Polygon");
+                        }
+                        if self.active_tool == Tool::MagicWand {
+                            fill_options_ui(ui, &mut self.wand_options);
+                        }
+                        if self.active_tool == Tool::Scale {
+                            ui.separator();
+                            ui.checkbox(&mut self.integer_scale, "Integer");
+                        }
+
+                        if let Some(c) = &mut self.canvas {
+                            ui.separator();
+
+                            let mut symmetry = *c.get_symmetry();
+                            egui::ComboBox::from_id_source("Symmetry")
+                                .selected_text(format!("Symmetry: {}", symmetry.mode.name()))
+                                .show_ui(ui, |ui| {
+                                    for mode in SymmetryMode::ALL {
+                                        ui.selectable_value(&mut symmetry.mode, mode, mode.name());
+                                    }
+                                });
+                            c.set_symmetry(symmetry);
+
+                            ui.separator();
+
+                            let mut tiling = *c.get_tiling();
+                            ui.checkbox(&mut tiling.wrap, "Wrap");
+                            ui.checkbox(&mut tiling.preview, "Tile preview");
+                            c.set_tiling(tiling);
+
+                            ui.separator();
+
+                            let mut onion_skin = *c.get_onion_skin();
+                            ui.checkbox(&mut onion_skin.enabled, "Onion skin");
+                            ui.add_enabled_ui(onion_skin.enabled, |ui| {
+                                ui.add(
+                                    egui::DragValue::new(&mut onion_skin.previous)
+                                        .clamp_range(0..=5)
+                                        .prefix("prev "),
+                                );
+                                ui.add(
+                                    egui::DragValue::new(&mut onion_skin.next)
+                                        .clamp_range(0..=5)
+                                        .prefix("next "),
+                                );
+                                ui.add(
+                                    egui::Slider::new(&mut onion_skin.opacity, 0.05..=1.0)
+                                        .text("opacity"),
+                                );
+                            });
+                            c.set_onion_skin(onion_skin);
+                        }
                     });
                 });
             });
@@ -306,71 +710,320 @@ impl Ruxel {
                         ui.heading("Layers");
 
                         // Need to check if canvas is opened yet
-                        match &mut self.canvas {
-                            Some(c) => {
-                                let num_layers = c.get_num_layers();
-                                let active_layer = c.get_active_layer();
-
-                                // Iterate over layers
-                                for i in 0..num_layers {
-                                    ui.horizontal(|ui| {
-                                        let button = egui::Button::new(c.get_layer_name(i))
-                                            .selected(i == active_layer);
-
-                                        let response = ui.add_sized(
-                                            [ui.available_width() / 1.25, ui.available_height()],
-                                            button,
-                                        );
-                                        if response.clicked() {
-                                            c.set_active_layer(i);
-                                        }
+                        if let Some(c) = &mut self.canvas {
+                            let num_layers = c.get_num_layers();
+                            let active_layer = c.get_active_layer();
 
-                                        let layers_to_show = c.get_layers_to_show_mut();
-                                        let to_show = layers_to_show.get_mut(i).unwrap();
+                            // Iterate over layers
+                            for i in 0..num_layers {
+                                ui.horizontal(|ui| {
+                                    let button = egui::Button::new(c.get_layer_name(i))
+                                        .selected(i == active_layer);
 
-                                        let checkbox = egui::Checkbox::new(to_show, "");
+                                    let response = ui.add_sized(
+                                        [ui.available_width() / 1.25, ui.available_height()],
+                                        button,
+                                    );
+                                    if response.clicked() {
+                                        c.set_active_layer(i);
+                                    }
 
-                                        ui.add_sized(ui.available_size(), checkbox);
-                                    });
-                                }
+                                    let layers_to_show = c.get_layers_to_show_mut();
+                                    let to_show = layers_to_show.get_mut(i).unwrap();
 
-                                ui.separator();
+                                    let checkbox = egui::Checkbox::new(to_show, "");
 
-                                // Add "+" and "-" button
-                                ui.horizontal(|ui| {
-                                    let plus_button = ui.add_sized(
-                                        [ui.available_width() / 2.0, ui.available_height()],
-                                        egui::Button::new("+"),
-                                    );
-                                    if plus_button.clicked() {
-                                        c.add_layer();
+                                    if ui.add_sized(ui.available_size(), checkbox).changed() {
+                                        let action = match c.is_layer_shown(i) {
+                                            true => "Show layer",
+                                            false => "Hide layer",
+                                        };
+                                        let action = format!("{} {}", action, c.get_layer_name(i));
+                                        c.update_image();
                                         self.change_manager
                                             .as_mut()
                                             .unwrap()
-                                            .push_new_state(c.create_state());
+                                            .push_change(c, action);
                                     }
+                                });
+                            }
+
+                            ui.separator();
+
+                            // Add "+" and "-" button
+                            ui.horizontal(|ui| {
+                                let plus_button = ui.add_sized(
+                                    [ui.available_width() / 2.0, ui.available_height()],
+                                    egui::Button::new("+"),
+                                );
+                                if plus_button.clicked() {
+                                    c.add_layer();
+                                    self.change_manager
+                                        .as_mut()
+                                        .unwrap()
+                                        .push_change(c, "Add layer");
+                                }
 
-                                    let minus_button = ui.add_sized(
+                                let minus_button = ui.add_enabled_ui(num_layers > 1, |ui| {
+                                    ui.add_sized(
                                         [ui.available_width(), ui.available_height()],
                                         egui::Button::new("-"),
+                                    )
+                                });
+                                let minus_button = minus_button.inner;
+                                if minus_button.clicked() {
+                                    let deleted_name = c.get_layer_name(active_layer).clone();
+                                    c.delete_layer(active_layer);
+                                    self.change_manager
+                                        .as_mut()
+                                        .unwrap()
+                                        .push_change(c, format!("Delete layer {}", deleted_name));
+                                }
+                            });
+                        }
+                    });
+                });
+            });
+    }
+
+    fn history_selection(&mut self, ui: &mut eframe::egui::Ui) {
+        if !self.history_panel.open {
+            return;
+        }
+
+        egui::SidePanel::new(self.history_panel.side, "History")
+            .resizable(true)
+            .default_width(150.0)
+            .width_range(80.0..=250.0)
+            .show_inside(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.heading("History");
+                    let dock_text = match self.history_panel.side {
+                        panel::Side::Left => ">",
+                        panel::Side::Right => "<",
+                    };
+                    let dock_button = ui
+                        .small_button(dock_text)
+                        .on_hover_text("Dock on the other side");
+                    if dock_button.clicked() {
+                        self.history_panel.switch_side();
+                    }
+                });
+                ui.separator();
+
+                // Need to check if canvas is opened yet
+                if let (Some(c), Some(cm)) = (&mut self.canvas, &mut self.change_manager) {
+                    let row_height = ui.spacing().interact_size.y;
+                    let indent = ui.spacing().indent;
+                    let mut jump_to = None;
+
+                    // Branches are indented under the state they split off from, states
+                    // that are not part of the canvas right now are greyed out
+                    egui::ScrollArea::vertical()
+                        .auto_shrink(false)
+                        .stick_to_bottom(true)
+                        .show_rows(ui, row_height, cm.history_len(), |ui, rows| {
+                            for entry in cm.history_entries(rows) {
+                                let text = if entry.applied {
+                                    RichText::new(entry.name)
+                                } else {
+                                    RichText::new(entry.name).weak()
+                                };
+                                ui.horizontal(|ui| {
+                                    ui.add_space(indent * entry.branch_depth as f32);
+                                    let label = ui.add_sized(
+                                        [ui.available_width(), row_height],
+                                        egui::SelectableLabel::new(entry.current, text),
                                     );
-                                    if minus_button.clicked() {
-                                        c.delete_layer(active_layer);
-                                        self.change_manager
-                                            .as_mut()
-                                            .unwrap()
-                                            .push_new_state(c.create_state());
+                                    if label.clicked() {
+                                        jump_to = Some(entry.id);
                                     }
                                 });
                             }
-                            None => (),
+                        });
+
+                    if let Some(id) = jump_to {
+                        cm.jump_to(c, id);
+                    }
+                }
+            });
+    }
+
+    fn timeline(&mut self, ui: &mut eframe::egui::Ui) {
+        egui::TopBottomPanel::bottom("Timeline")
+            .resizable(false)
+            .min_height(32.0)
+            .show_inside(ui, |ui| {
+                // Need to check if canvas is opened yet
+                if let Some(c) = &mut self.canvas {
+                    let num_frames = c.get_num_frames();
+                    let active_frame = c.get_active_frame();
+
+                    // Advance the animation once the current frame has been shown long enough
+                    if let Some(started) = self.playback {
+                        let now = ui.input(|i| i.time);
+                        let duration = c.get_frame_duration(active_frame) as f64 / 1000.0;
+                        if now - started >= duration {
+                            c.set_active_frame((active_frame + 1) % num_frames);
+                            self.playback = Some(now);
+                        }
+                        ui.ctx()
+                            .request_repaint_after(std::time::Duration::from_secs_f64(
+                                (duration - (now - started)).max(0.0),
+                            ));
+                    }
+
+                    ui.horizontal_centered(|ui| {
+                        let play_button = ui.selectable_label(
+                            self.playback.is_some(),
+                            if self.playback.is_some() {
+                                "Stop"
+                            } else {
+                                "Play"
+                            },
+                        );
+                        if play_button.clicked() {
+                            self.playback = match self.playback {
+                                Some(_) => None,
+                                None => Some(ui.input(|i| i.time)),
+                            };
+                        }
+
+                        ui.separator();
+
+                        let mut action = None;
+                        if ui.button("+").on_hover_text("New frame").clicked() {
+                            c.add_frame();
+                            action = Some(String::from("Add frame"));
+                        }
+                        if ui.button("Duplicate").clicked() {
+                            c.duplicate_frame();
+                            action = Some(String::from("Duplicate frame"));
                         }
+                        let minus_button = ui
+                            .add_enabled(num_frames > 1, egui::Button::new("-"))
+                            .on_hover_text("Delete frame");
+                        if minus_button.clicked() {
+                            c.delete_frame(active_frame);
+                            action = Some(format!("Delete frame {}", active_frame + 1));
+                        }
+                        let left_button = ui
+                            .add_enabled(active_frame > 0, egui::Button::new("<"))
+                            .on_hover_text("Move frame left");
+                        if left_button.clicked() {
+                            c.move_frame(active_frame, active_frame - 1);
+                            action = Some(String::from("Move frame left"));
+                        }
+                        let right_button = ui
+                            .add_enabled(active_frame + 1 < num_frames, egui::Button::new(">"))
+                            .on_hover_text("Move frame right");
+                        if right_button.clicked() {
+                            c.move_frame(active_frame, active_frame + 1);
+                            action = Some(String::from("Move frame right"));
+                        }
+
+                        ui.separator();
+
+                        let active_frame = c.get_active_frame();
+                        let duration_response = ui.add(
+                            egui::DragValue::new(c.get_frame_duration_mut(active_frame))
+                                .speed(1)
+                                .clamp_range(10..=10000)
+                                .suffix("ms"),
+                        );
+                        if duration_response.drag_stopped()
+                            || (duration_response.changed() && !duration_response.dragged())
+                        {
+                            action = Some(String::from("Change frame duration"));
+                        }
+                        ui.label("Duration");
+
+                        if let Some(action) = action {
+                            self.change_manager.as_mut().unwrap().push_change(c, action);
+                        }
+
+                        ui.separator();
+
+                        egui::ScrollArea::horizontal().show(ui, |ui| {
+                            for i in 0..c.get_num_frames() {
+                                let button = egui::Button::new((i + 1).to_string())
+                                    .selected(i == active_frame)
+                                    .min_size(Vec2 { x: 32.0, y: 0.0 });
+                                if ui.add(button).clicked() {
+                                    c.set_active_frame(i);
+                                }
+                            }
+                        });
                     });
-                });
+                }
             });
     }
 
+    /// Copies the selected pixels of the active layer to the clipboard.
+    fn copy(&mut self) {
+        if let Some(clipboard) = self.canvas.as_ref().and_then(Canvas::copy_selection) {
+            self.clipboard = Some(clipboard);
+        }
+    }
+
+    fn cut(&mut self) {
+        self.copy();
+        if let (Some(c), Some(cm)) = (&mut self.canvas, &mut self.change_manager) {
+            c.delete_selection();
+            cm.push_change(c, "Cut");
+        }
+    }
+
+    /// Pastes the clipboard onto the active layer, where it was copied from.
+    fn paste(&mut self) {
+        if let (Some(c), Some(cm), Some(clipboard)) =
+            (&mut self.canvas, &mut self.change_manager, &self.clipboard)
+        {
+            c.paste(clipboard);
+            cm.push_change(c, "Paste");
+        }
+    }
+
+    fn select_all(&mut self) {
+        if let (Some(c), Some(cm)) = (&mut self.canvas, &mut self.change_manager) {
+            c.select_all();
+            cm.push_change(c, "Select all");
+        }
+    }
+
+    fn deselect(&mut self) {
+        if let (Some(c), Some(cm)) = (&mut self.canvas, &mut self.change_manager) {
+            c.set_selection(None);
+            cm.push_change(c, "Deselect");
+        }
+    }
+
+    fn invert_selection(&mut self) {
+        if let (Some(c), Some(cm)) = (&mut self.canvas, &mut self.change_manager) {
+            c.invert_selection();
+            cm.push_change(c, "Invert selection");
+        }
+    }
+
+    fn delete_selection(&mut self) {
+        if let (Some(c), Some(cm)) = (&mut self.canvas, &mut self.change_manager) {
+            c.delete_selection();
+            cm.push_change(c, "Delete selection");
+        }
+    }
+
     fn canvas_ui(&mut self, ui: &mut eframe::egui::Ui) {
+        let rect_style = ShapeStyle {
+            mode: self.rect_mode,
+            width: self.tool_size,
+            corner_radius: self.corner_radius,
+            color: self.active_color,
+            fill_color: self.fill_color,
+        };
+        let ellipse_style = ShapeStyle {
+            mode: self.ellipse_mode,
+            ..rect_style
+        };
         CentralPanel::default().show_inside(ui, |ui| {
             if let Some(c) = &mut self.canvas {
                 Frame::canvas(ui.style()).show(ui, |ui| {
@@ -385,10 +1038,10 @@ impl Ruxel {
                     };
                     if screen_center != *c.get_screen_center() {
                         *c.get_screen_center_mut() = screen_center;
-                        c.update_squares();
                     }
 
-                    let mut squares = c.get_squares_unhidden();
+                    c.update_textures(ui.ctx());
+                    let mut shapes = c.get_image_shapes();
                     let active_layer = c.get_active_layer();
 
                     if response.dragged_by(PointerButton::Middle) {
@@ -402,7 +1055,27 @@ impl Ruxel {
                                     _ => {}
                                 }
                             }
+                            // Grabbing a symmetry handle moves its axis instead of using
+                            // the tool
+                            if let (true, Some(pointer_pos)) =
+                                (response.drag_started(), response.interact_pointer_pos())
+                            {
+                                self.symmetry_drag =
+                                    c.get_symmetry_handles().iter().position(|handle| {
+                                        handle.is_some_and(|h| h.expand(2.0).contains(pointer_pos))
+                                    });
+                            }
                             match self.active_tool {
+                                _ if self.symmetry_drag.is_some() => {
+                                    if let (Some(handle), Some(pointer_pos)) =
+                                        (self.symmetry_drag, i.pointer.latest_pos())
+                                    {
+                                        c.drag_symmetry_axis(handle, &pointer_pos);
+                                    }
+                                    if response.drag_stopped() {
+                                        self.symmetry_drag = None;
+                                    }
+                                }
                                 Tool::Draw => {
                                     if let (true, Some(pointer_pos)) = (
                                         response.clicked() || response.dragged(),
@@ -412,17 +1085,19 @@ impl Ruxel {
                                             &pointer_pos,
                                             self.tool_size,
                                             self.active_color,
+                                            self.pixel_perfect,
                                         );
                                     } else if let Some(pointer_pos) = i.pointer.latest_pos() {
-                                        squares.extend(
+                                        shapes.extend(
                                             c.get_circle_brush(&pointer_pos, self.tool_size),
                                         );
                                     }
                                     if response.drag_stopped() {
+                                        c.end_stroke();
                                         self.change_manager
                                             .as_mut()
                                             .unwrap()
-                                            .push_new_state(c.create_state());
+                                            .push_change(c, "Brush stroke");
                                     }
                                 }
                                 Tool::Erase => {
@@ -434,71 +1109,164 @@ impl Ruxel {
                                             &pointer_pos,
                                             self.tool_size,
                                             Color32::TRANSPARENT,
+                                            false,
                                         );
-                                        squares.extend(
+                                        shapes.extend(
                                             c.get_circle_brush(&pointer_pos, self.tool_size),
                                         );
                                     } else if let Some(pointer_pos) = i.pointer.latest_pos() {
-                                        squares.extend(
+                                        shapes.extend(
                                             c.get_circle_brush(&pointer_pos, self.tool_size),
                                         );
                                     }
                                     if response.drag_stopped() {
+                                        c.end_stroke();
                                         self.change_manager
                                             .as_mut()
                                             .unwrap()
-                                            .push_new_state(c.create_state());
+                                            .push_change(c, "Erase");
                                     }
                                 }
                                 Tool::Fill => {
                                     if let (true, Some(pointer_pos)) =
                                         (response.drag_started(), response.interact_pointer_pos())
                                     {
-                                        if let Some(t) =
-                                            c.get_pixel_from_screen_cords(pointer_pos, active_layer)
+                                        if c.fill(
+                                            &pointer_pos,
+                                            &self.active_color,
+                                            &self.fill_options,
+                                        )
+                                        .is_ok()
                                         {
-                                            let target_color = *t;
-                                            if c.fill(
-                                                &pointer_pos,
-                                                &target_color,
-                                                &self.active_color,
-                                            )
-                                            .is_ok()
-                                            {
-                                                self.change_manager
-                                                    .as_mut()
-                                                    .unwrap()
-                                                    .push_new_state(c.create_state())
-                                            }
+                                            self.change_manager
+                                                .as_mut()
+                                                .unwrap()
+                                                .push_change(c, "Fill")
                                         }
                                     }
                                 }
                                 Tool::Rectangle(start) => {
+                                    // Shift draws squares
+                                    let square = i.modifiers.shift;
                                     if let (true, None, Some(pointer_pos)) = (
                                         response.clicked() || response.dragged(),
                                         start,
                                         response.interact_pointer_pos(),
                                     ) {
                                         self.active_tool = Tool::Rectangle(Some(pointer_pos));
-                                        squares.push(c.get_rect_brush(&pointer_pos, &pointer_pos));
+                                        shapes.extend(c.get_rect_brush(
+                                            &pointer_pos,
+                                            &pointer_pos,
+                                            &rect_style,
+                                            square,
+                                        ));
                                     } else if let Some(pointer_pos) = i.pointer.latest_pos() {
                                         let start_pos = start.unwrap_or(pointer_pos);
-                                        squares.push(c.get_rect_brush(&start_pos, &pointer_pos));
+                                        shapes.extend(c.get_rect_brush(
+                                            &start_pos,
+                                            &pointer_pos,
+                                            &rect_style,
+                                            square,
+                                        ));
                                     }
                                     if let (true, Some(start_pos), Some(end_pos)) =
                                         (response.drag_stopped(), start, i.pointer.latest_pos())
                                     {
                                         self.active_tool = Tool::Rectangle(None);
-                                        c.set_pixels_from_rect_brush(
+                                        c.set_pixels_from_rect(
+                                            &start_pos,
+                                            &end_pos,
+                                            &rect_style,
+                                            square,
+                                        );
+
+                                        self.change_manager
+                                            .as_mut()
+                                            .unwrap()
+                                            .push_change(c, "Rectangle");
+                                    }
+                                }
+                                Tool::Ellipse(start) => {
+                                    // Shift draws circles
+                                    let circle = i.modifiers.shift;
+                                    if let (true, None, Some(pointer_pos)) = (
+                                        response.clicked() || response.dragged(),
+                                        start,
+                                        response.interact_pointer_pos(),
+                                    ) {
+                                        self.active_tool = Tool::Ellipse(Some(pointer_pos));
+                                        shapes.extend(c.get_ellipse_brush(
+                                            &pointer_pos,
+                                            &pointer_pos,
+                                            &ellipse_style,
+                                            circle,
+                                        ));
+                                    } else if let Some(pointer_pos) = i.pointer.latest_pos() {
+                                        let start_pos = start.unwrap_or(pointer_pos);
+                                        shapes.extend(c.get_ellipse_brush(
+                                            &start_pos,
+                                            &pointer_pos,
+                                            &ellipse_style,
+                                            circle,
+                                        ));
+                                    }
+                                    if let (true, Some(start_pos), Some(end_pos)) =
+                                        (response.drag_stopped(), start, i.pointer.latest_pos())
+                                    {
+                                        self.active_tool = Tool::Ellipse(None);
+                                        c.set_pixels_from_ellipse(
                                             &start_pos,
                                             &end_pos,
+                                            &ellipse_style,
+                                            circle,
+                                        );
+
+                                        self.change_manager
+                                            .as_mut()
+                                            .unwrap()
+                                            .push_change(c, "Ellipse");
+                                    }
+                                }
+                                Tool::Line(start) => {
+                                    // Shift snaps the line to straight, diagonal and 2:1
+                                    let snap = i.modifiers.shift;
+                                    if let (true, None, Some(pointer_pos)) = (
+                                        response.clicked() || response.dragged(),
+                                        start,
+                                        response.interact_pointer_pos(),
+                                    ) {
+                                        self.active_tool = Tool::Line(Some(pointer_pos));
+                                        shapes.extend(c.get_line_brush(
+                                            &pointer_pos,
+                                            &pointer_pos,
+                                            self.tool_size,
+                                            snap,
+                                        ));
+                                    } else if let Some(pointer_pos) = i.pointer.latest_pos() {
+                                        let start_pos = start.unwrap_or(pointer_pos);
+                                        shapes.extend(c.get_line_brush(
+                                            &start_pos,
+                                            &pointer_pos,
+                                            self.tool_size,
+                                            snap,
+                                        ));
+                                    }
+                                    if let (true, Some(start_pos), Some(end_pos)) =
+                                        (response.drag_stopped(), start, i.pointer.latest_pos())
+                                    {
+                                        self.active_tool = Tool::Line(None);
+                                        c.set_pixels_from_line(
+                                            &start_pos,
+                                            &end_pos,
+                                            self.tool_size,
                                             self.active_color,
+                                            snap,
                                         );
 
                                         self.change_manager
                                             .as_mut()
                                             .unwrap()
-                                            .push_new_state(c.create_state());
+                                            .push_change(c, "Line");
                                     }
                                 }
                                 Tool::Eyedrop => {
@@ -516,12 +1284,194 @@ impl Ruxel {
                                         }
                                     }
                                 }
+                                Tool::Select(start) => {
+                                    // Dragging the selection moves it, dragging anywhere
+                                    // else or with a modifier selects a new rectangle
+                                    let mode = selection_mode(&i.modifiers, self.selection_mode);
+                                    let modified = i.modifiers.shift || i.modifiers.alt;
+                                    if let (true, Some(pointer_pos)) =
+                                        (response.drag_started(), response.interact_pointer_pos())
+                                    {
+                                        if modified || !c.begin_move(&pointer_pos) {
+                                            self.active_tool = Tool::Select(Some(pointer_pos));
+                                        }
+                                    } else if let (true, None, Some(pointer_pos)) =
+                                        (response.dragged(), start, response.interact_pointer_pos())
+                                    {
+                                        c.drag_move(&pointer_pos);
+                                    }
+                                    if let (Some(start_pos), Some(pointer_pos)) =
+                                        (start, i.pointer.latest_pos())
+                                    {
+                                        shapes.extend(c.get_marquee_shapes(
+                                            &start_pos,
+                                            &pointer_pos,
+                                            i.time,
+                                        ));
+                                    }
+                                    if response.drag_stopped() {
+                                        match (start, i.pointer.latest_pos()) {
+                                            (Some(start_pos), Some(end_pos)) => {
+                                                self.active_tool = Tool::Select(None);
+                                                c.select_rect(&start_pos, &end_pos, mode);
+                                                self.change_manager
+                                                    .as_mut()
+                                                    .unwrap()
+                                                    .push_change(c, "Select");
+                                            }
+                                            _ => {
+                                                c.end_move();
+                                                self.change_manager
+                                                    .as_mut()
+                                                    .unwrap()
+                                                    .push_change(c, "Move selection");
+                                            }
+                                        }
+                                    }
+                                }
+                                Tool::Lasso => {
+                                    let mode = selection_mode(&i.modifiers, self.selection_mode);
+                                    if self.polygon_lasso {
+                                        // Each click adds a corner, clicking the first corner
+                                        // again or double clicking closes the polygon
+                                        if let (true, Some(pointer_pos)) = (
+                                            response.drag_started(),
+                                            response.interact_pointer_pos(),
+                                        ) {
+                                            let closes =
+                                                self.lasso_points.first().is_some_and(|&first| {
+                                                    c.pixel_to_screen_pos(first)
+                                                        .distance(pointer_pos)
+                                                        < LASSO_CLOSE_DISTANCE
+                                                });
+                                            if closes
+                                                || i.pointer
+                                                    .button_double_clicked(PointerButton::Primary)
+                                            {
+                                                c.select_polygon(&self.lasso_points, mode);
+                                                self.change_manager
+                                                    .as_mut()
+                                                    .unwrap()
+                                                    .push_change(c, "Lasso");
+                                                self.lasso_points.clear();
+                                            } else {
+                                                self.lasso_points
+                                                    .push(c.screen_to_pixel_pos(&pointer_pos));
+                                            }
+                                        }
+                                        if i.key_pressed(Key::Escape) {
+                                            self.lasso_points.clear();
+                                        }
+                                    } else {
+                                        if response.drag_started() {
+                                            self.lasso_points.clear();
+                                        }
+                                        if let (true, Some(pointer_pos)) =
+                                            (response.dragged(), response.interact_pointer_pos())
+                                        {
+                                            self.lasso_points
+                                                .push(c.screen_to_pixel_pos(&pointer_pos));
+                                        }
+                                        if response.drag_stopped() {
+                                            // A click without drawing anything deselects
+                                            if self.lasso_points.len() < 3 {
+                                                if mode == SelectionMode::Replace {
+                                                    c.set_selection(None);
+                                                }
+                                            } else {
+                                                c.select_polygon(&self.lasso_points, mode);
+                                            }
+                                            self.change_manager
+                                                .as_mut()
+                                                .unwrap()
+                                                .push_change(c, "Lasso");
+                                            self.lasso_points.clear();
+                                        }
+                                    }
+                                    if !self.lasso_points.is_empty() {
+                                        shapes.extend(c.get_lasso_shapes(
+                                            &self.lasso_points,
+                                            i.pointer.latest_pos(),
+                                            i.time,
+                                        ));
+                                    }
+                                }
+                                Tool::Scale => {
+                                    // Scales the selection, or the whole layer, by dragging
+                                    // one of the handles around it
+                                    let bounds = c.get_transform_bounds();
+                                    if let (true, Some(pointer_pos)) =
+                                        (response.drag_started(), response.interact_pointer_pos())
+                                    {
+                                        self.scale_drag = c
+                                            .get_scale_handles(bounds)
+                                            .iter()
+                                            .position(|handle| {
+                                                handle.expand(2.0).contains(pointer_pos)
+                                            })
+                                            .map(|handle| (handle, bounds));
+                                    }
+                                    if let (Some((handle, _)), Some(pointer_pos)) =
+                                        (self.scale_drag, i.pointer.latest_pos())
+                                    {
+                                        let scaled = c.drag_scale_handle(
+                                            bounds,
+                                            handle,
+                                            &pointer_pos,
+                                            self.integer_scale,
+                                        );
+                                        self.scale_drag = Some((handle, scaled));
+                                    }
+                                    let shown = self.scale_drag.map_or(bounds, |(_, b)| b);
+                                    shapes.extend(c.get_scale_shapes(shown, i.time));
+
+                                    if response.drag_stopped() {
+                                        if let Some((_, scaled)) = self.scale_drag.take() {
+                                            if scaled != bounds {
+                                                c.scale_selection(scaled);
+                                                self.change_manager
+                                                    .as_mut()
+                                                    .unwrap()
+                                                    .push_change(c, "Scale");
+                                            }
+                                        }
+                                    }
+                                }
+                                Tool::MagicWand => {
+                                    if let (true, Some(pointer_pos)) =
+                                        (response.drag_started(), response.interact_pointer_pos())
+                                    {
+                                        let mode =
+                                            selection_mode(&i.modifiers, self.selection_mode);
+                                        c.select_similar(&pointer_pos, &self.wand_options, mode);
+                                        self.change_manager
+                                            .as_mut()
+                                            .unwrap()
+                                            .push_change(c, "Magic wand");
+                                    }
+                                }
                             }
                         });
                     }
 
+                    if c.get_selection().is_some()
+                        || !self.lasso_points.is_empty()
+                        || self.active_tool == Tool::Scale
+                    {
+                        shapes.extend(c.get_selection_shapes(ui.input(|i| i.time)));
+                        // Keep the marching ants marching
+                        ui.ctx()
+                            .request_repaint_after(std::time::Duration::from_secs_f64(
+                                MARCHING_ANTS_STEP,
+                            ));
+                    }
+
+                    if c.get_symmetry().mode != SymmetryMode::None {
+                        shapes.extend(c.get_symmetry_shapes());
+                    }
+
                     // Draw shapes
-                    painter.extend(squares);
+                    painter.extend(shapes);
                 });
             } else {
                 ui.vertical_centered(|ui| {
@@ -542,7 +1492,9 @@ impl Ruxel {
         self.menu_selection(ui);
         self.color_selection(ui);
         self.layer_selection(ui);
+        self.history_selection(ui);
         self.tool_selection(ui);
+        self.timeline(ui);
         self.canvas_ui(ui);
     }
 
@@ -558,7 +1510,7 @@ impl Ruxel {
                     ui.add(
                         egui::DragValue::new(&mut self.new_file_modal.width)
                             .speed(1)
-                            .clamp_range(1..=128)
+                            .clamp_range(1..=MAX_CANVAS_SIZE)
                             .suffix("px"),
                     );
                     ui.label("Width")
@@ -567,7 +1519,7 @@ impl Ruxel {
                     ui.add(
                         egui::DragValue::new(&mut self.new_file_modal.height)
                             .speed(1)
-                            .clamp_range(1..=128)
+                            .clamp_range(1..=MAX_CANVAS_SIZE)
                             .suffix("px"),
                     );
                     ui.label("Height")
@@ -590,13 +1542,258 @@ impl Ruxel {
                     if button.clicked() {
                         let canvas =
                             Canvas::new(self.new_file_modal.width, self.new_file_modal.height);
-                        self.change_manager = Some(ChangeManager::new(canvas.create_state()));
+                        self.change_manager = Some(ChangeManager::new());
                         self.canvas = Some(canvas);
                         self.new_file_modal.show_modal = false;
                     }
                 });
             });
     }
+
+    fn export_modal_window(&mut self, ctx: &Context) {
+        egui::Window::new("Export")
+            .open(&mut self.export_modal.show_modal_toggle)
+            .collapsible(false)
+            .resizable(false)
+            .pivot(Align2::CENTER_CENTER)
+            .fixed_pos(ctx.screen_rect().center())
+            .show(ctx, |ui| {
+                let options = &mut self.export_modal.options;
+
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut options.scale)
+                            .speed(1)
+                            .clamp_range(1..=MAX_EXPORT_SCALE)
+                            .suffix("x"),
+                    );
+                    ui.label("Scale")
+                });
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_source("Export Format")
+                        .selected_text(options.format.name())
+                        .show_ui(ui, |ui| {
+                            for format in ExportFormat::ALL {
+                                ui.selectable_value(&mut options.format, format, format.name());
+                            }
+                        });
+                    ui.label("Format")
+                });
+                if options.format.is_animated() {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut options.loop_count).speed(1));
+                        ui.label("Plays (0 loops forever)")
+                    });
+                }
+                ui.checkbox(&mut options.include_hidden, "Include hidden layers");
+                ui.checkbox(&mut options.crop_to_content, "Crop to content");
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut options.fill_background, "Fill background");
+                    ui.add_enabled_ui(options.fill_background, |ui| {
+                        ui.color_edit_button_srgba(&mut options.background);
+                    });
+                });
+
+                ui.add_space(ui.spacing().item_spacing.y);
+
+                ui.horizontal(|ui| {
+                    let button = ui.add_sized(
+                        [100.0, 30.0],
+                        egui::Button::new("Cancel").stroke(ui.visuals().window_stroke()),
+                    );
+                    if button.clicked() {
+                        self.export_modal.show_modal = false;
+                    }
+                    let button = ui.add_sized(
+                        [100.0, 30.0],
+                        egui::Button::new("Export").stroke(ui.visuals().window_stroke()),
+                    );
+                    if button.clicked() {
+                        if let Some(c) = &mut self.canvas {
+                            c.set_export_options(self.export_modal.options);
+                            match self.file_interactions.export_file(c) {
+                                Ok(Some(path)) => self
+                                    .notifications
+                                    .info(format!("Exported {}", path.display())),
+                                Ok(None) => {}
+                                Err(e) => {
+                                    self.notifications.error(format!("Could not export {}", e))
+                                }
+                            }
+                        }
+                        self.export_modal.show_modal = false;
+                    }
+                });
+            });
+    }
+
+    fn sprite_sheet_modal_window(&mut self, ctx: &Context) {
+        egui::Window::new("Export Sprite Sheet")
+            .open(&mut self.sprite_sheet_modal.show_modal_toggle)
+            .collapsible(false)
+            .resizable(false)
+            .pivot(Align2::CENTER_CENTER)
+            .fixed_pos(ctx.screen_rect().center())
+            .show(ctx, |ui| {
+                let options = &mut self.sprite_sheet_modal.options;
+
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_source("Sheet Layout")
+                        .selected_text(options.layout.name())
+                        .show_ui(ui, |ui| {
+                            for layout in SheetLayout::ALL {
+                                ui.selectable_value(&mut options.layout, layout, layout.name());
+                            }
+                        });
+                    ui.label("Layout")
+                });
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_id_source("Atlas Format")
+                        .selected_text(options.atlas_format.name())
+                        .show_ui(ui, |ui| {
+                            for format in AtlasFormat::ALL {
+                                ui.selectable_value(
+                                    &mut options.atlas_format,
+                                    format,
+                                    format.name(),
+                                );
+                            }
+                        });
+                    ui.label("Atlas")
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut options.scale)
+                            .speed(1)
+                            .clamp_range(1..=MAX_EXPORT_SCALE)
+                            .suffix("x"),
+                    );
+                    ui.label("Scale")
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut options.padding)
+                            .speed(1)
+                            .clamp_range(0..=256)
+                            .suffix("px"),
+                    );
+                    ui.label("Padding")
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut options.spacing)
+                            .speed(1)
+                            .clamp_range(0..=256)
+                            .suffix("px"),
+                    );
+                    ui.label("Spacing")
+                });
+                ui.checkbox(&mut options.split_layers, "Split layers");
+                ui.checkbox(&mut options.include_hidden, "Include hidden layers");
+
+                ui.add_space(ui.spacing().item_spacing.y);
+
+                ui.horizontal(|ui| {
+                    let button = ui.add_sized(
+                        [100.0, 30.0],
+                        egui::Button::new("Cancel").stroke(ui.visuals().window_stroke()),
+                    );
+                    if button.clicked() {
+                        self.sprite_sheet_modal.show_modal = false;
+                    }
+                    let button = ui.add_sized(
+                        [100.0, 30.0],
+                        egui::Button::new("Export").stroke(ui.visuals().window_stroke()),
+                    );
+                    if button.clicked() {
+                        if let Some(c) = &self.canvas {
+                            match self
+                                .file_interactions
+                                .export_sprite_sheet(c, &self.sprite_sheet_modal.options)
+                            {
+                                Ok(Some(path)) => self
+                                    .notifications
+                                    .info(format!("Exported {}", path.display())),
+                                Ok(None) => {}
+                                Err(e) => {
+                                    self.notifications.error(format!("Could not export {}", e))
+                                }
+                            }
+                        }
+                        self.sprite_sheet_modal.show_modal = false;
+                    }
+                });
+            });
+    }
+
+    fn rotate_modal_window(&mut self, ctx: &Context) {
+        egui::Window::new("Rotate")
+            .open(&mut self.rotate_modal.show_modal_toggle)
+            .collapsible(false)
+            .resizable(false)
+            .pivot(Align2::CENTER_CENTER)
+            .fixed_pos(ctx.screen_rect().center())
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut self.rotate_modal.angle)
+                            .speed(1)
+                            .clamp_range(-360.0..=360.0)
+                            .suffix("°"),
+                    );
+                    ui.label("Angle (clockwise)")
+                });
+
+                ui.add_space(ui.spacing().item_spacing.y);
+
+                ui.horizontal(|ui| {
+                    let button = ui.add_sized(
+                        [100.0, 30.0],
+                        egui::Button::new("Cancel").stroke(ui.visuals().window_stroke()),
+                    );
+                    if button.clicked() {
+                        self.rotate_modal.show_modal = false;
+                    }
+                    let button = ui.add_sized(
+                        [100.0, 30.0],
+                        egui::Button::new("Rotate").stroke(ui.visuals().window_stroke()),
+                    );
+                    if button.clicked() {
+                        if let (Some(c), Some(cm)) = (&mut self.canvas, &mut self.change_manager) {
+                            c.rotate_selection(self.rotate_modal.angle);
+                            cm.push_change(c, format!("Rotate {}°", self.rotate_modal.angle));
+                        }
+                        self.rotate_modal.show_modal = false;
+                    }
+                });
+            });
+    }
+}
+
+/// Shift adds to the selection, Alt subtracts from it and both together intersect with
+/// it. Without either, the mode picked in the toolbar is used.
+fn selection_mode(modifiers: &Modifiers, picked: SelectionMode) -> SelectionMode {
+    match (modifiers.shift, modifiers.alt) {
+        (true, true) => SelectionMode::Intersect,
+        (true, false) => SelectionMode::Add,
+        (false, true) => SelectionMode::Subtract,
+        (false, false) => picked,
+    }
+}
+
+/// Options shared by the fill tool and the magic wand.
+fn fill_options_ui(ui: &mut Ui, options: &mut FillOptions) {
+    ui.add(
+        egui::Slider::new(&mut options.tolerance, 0..=100)
+            .text("tolerance")
+            .suffix("%"),
+    );
+    ui.checkbox(&mut options.contiguous, "Contiguous");
+    ui.add_enabled(
+        options.contiguous,
+        egui::Checkbox::new(&mut options.diagonal, "8-way"),
+    );
+    ui.checkbox(&mut options.sample_merged, "Sample merged");
 }
 
 impl eframe::App for Ruxel {
@@ -605,7 +1802,21 @@ impl eframe::App for Ruxel {
             self.new_file_modal_window(ctx)
         }
 
+        if self.export_modal.is_active() {
+            self.export_modal_window(ctx)
+        }
+
+        if self.sprite_sheet_modal.is_active() {
+            self.sprite_sheet_modal_window(ctx)
+        }
+
+        if self.rotate_modal.is_active() {
+            self.rotate_modal_window(ctx)
+        }
+
         ctx.set_visuals(visuals(CAT_FLAVOR, ctx.style().visuals.clone()));
         eframe::egui::CentralPanel::default().show(ctx, |ui| self.ui(ui));
+
+        self.notifications.show(ctx);
     }
 }
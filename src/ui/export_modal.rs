@@ -0,0 +1,21 @@
+use crate::export::ExportOptions;
+
+#[derive(Default)]
+pub struct ExportModal {
+    pub options: ExportOptions,
+    pub show_modal: bool,
+    pub show_modal_toggle: bool,
+}
+
+impl ExportModal {
+    /// Opens the modal with the options last used for the canvas.
+    pub fn activate(&mut self, options: ExportOptions) {
+        self.options = options;
+        self.show_modal = true;
+        self.show_modal_toggle = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.show_modal && self.show_modal_toggle
+    }
+}
@@ -0,0 +1,25 @@
+use egui::panel::Side;
+
+pub struct HistoryPanel {
+    pub open: bool,
+    pub side: Side,
+}
+
+impl Default for HistoryPanel {
+    fn default() -> Self {
+        HistoryPanel {
+            open: true,
+            side: Side::Right,
+        }
+    }
+}
+
+impl HistoryPanel {
+    /// Docks the panel on the other side of the canvas.
+    pub fn switch_side(&mut self) {
+        self.side = match self.side {
+            Side::Left => Side::Right,
+            Side::Right => Side::Left,
+        };
+    }
+}
@@ -0,0 +1,97 @@
+use egui::*;
+
+const INFO_DURATION: f64 = 4.0;
+const ERROR_DURATION: f64 = 10.0;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum NotificationKind {
+    Info,
+    Error,
+}
+
+struct Notification {
+    kind: NotificationKind,
+    message: String,
+    /// Set the first time the notification is drawn.
+    shown_at: Option<f64>,
+}
+
+/// Toasts stacked in the bottom right corner. They never block the canvas, disappear
+/// after a few seconds and can be dismissed early by clicking them.
+#[derive(Default)]
+pub struct Notifications {
+    entries: Vec<Notification>,
+}
+
+impl Notifications {
+    pub fn info(&mut self, message: impl Into<String>) {
+        self.push(NotificationKind::Info, message.into());
+    }
+
+    pub fn error(&mut self, message: impl Into<String>) {
+        self.push(NotificationKind::Error, message.into());
+    }
+
+    fn push(&mut self, kind: NotificationKind, message: String) {
+        self.entries.push(Notification {
+            kind,
+            message,
+            shown_at: None,
+        });
+    }
+
+    pub fn show(&mut self, ctx: &Context) {
+        if self.entries.is_empty() {
+            return;
+        }
+
+        let now = ctx.input(|i| i.time);
+        self.entries.retain(|n| match n.shown_at {
+            Some(shown_at) => now - shown_at < n.duration(),
+            None => true,
+        });
+
+        let mut dismissed = None;
+        egui::Area::new(Id::new("Notifications"))
+            .anchor(Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+            .order(Order::Foreground)
+            .interactable(true)
+            .show(ctx, |ui| {
+                for (i, n) in self.entries.iter_mut().enumerate() {
+                    let shown_at = *n.shown_at.get_or_insert(now);
+                    ctx.request_repaint_after(std::time::Duration::from_secs_f64(
+                        (n.duration() - (now - shown_at)).max(0.0),
+                    ));
+
+                    let color = match n.kind {
+                        NotificationKind::Info => ui.visuals().text_color(),
+                        NotificationKind::Error => ui.visuals().error_fg_color,
+                    };
+                    let response = Frame::popup(ui.style())
+                        .stroke(Stroke::new(1.0, color))
+                        .show(ui, |ui| {
+                            ui.set_max_width(300.0);
+                            ui.label(RichText::new(&n.message).color(color));
+                        })
+                        .response
+                        .interact(Sense::click());
+                    if response.on_hover_text("Click to dismiss").clicked() {
+                        dismissed = Some(i);
+                    }
+                }
+            });
+
+        if let Some(i) = dismissed {
+            self.entries.remove(i);
+        }
+    }
+}
+
+impl Notification {
+    fn duration(&self) -> f64 {
+        match self.kind {
+            NotificationKind::Info => INFO_DURATION,
+            NotificationKind::Error => ERROR_DURATION,
+        }
+    }
+}
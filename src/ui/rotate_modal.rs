@@ -0,0 +1,27 @@
+pub struct RotateModal {
+    /// Clockwise, in degrees.
+    pub angle: f32,
+    pub show_modal: bool,
+    pub show_modal_toggle: bool,
+}
+
+impl Default for RotateModal {
+    fn default() -> Self {
+        RotateModal {
+            angle: 45.0,
+            show_modal: false,
+            show_modal_toggle: false,
+        }
+    }
+}
+
+impl RotateModal {
+    pub fn activate(&mut self) {
+        self.show_modal = true;
+        self.show_modal_toggle = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.show_modal && self.show_modal_toggle
+    }
+}
@@ -0,0 +1,19 @@
+use crate::sprite_sheet::SpriteSheetOptions;
+
+#[derive(Default)]
+pub struct SpriteSheetModal {
+    pub options: SpriteSheetOptions,
+    pub show_modal: bool,
+    pub show_modal_toggle: bool,
+}
+
+impl SpriteSheetModal {
+    pub fn activate(&mut self) {
+        self.show_modal = true;
+        self.show_modal_toggle = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.show_modal && self.show_modal_toggle
+    }
+}